@@ -0,0 +1,13 @@
+import hashlib
+
+
+# Called once when the script loads. Each entry is changed from the request at
+# its index (the first without one) and together they replace the requests
+# murk would send, so nothing has to call into the script per request.
+def init_requests():
+    requests = []
+    for job in range(10):
+        path = "jobs/{}".format(job)
+        sig = hashlib.sha256(path.encode()).hexdigest()[:16]
+        requests.append({"url": "{}?sig={}".format(path, sig)})
+    return requests
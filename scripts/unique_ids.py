@@ -0,0 +1,12 @@
+import uuid
+
+
+# Called before every request. Return None to send the request murk picked,
+# the index of a request to send instead, or a dict of changes to make.
+def make_request(user_id):
+    return {
+        "headers": {
+            "x-request-id": str(uuid.uuid4()),
+            "x-user-id": str(user_id),
+        },
+    }
@@ -0,0 +1,696 @@
+//! A connector for the hyper client which keeps track of when connections are made and how they
+//! are used. This information is attached to every response so the time spent in each phase of a
+//! request can be worked out.
+//!
+//! It also handles TLS for https URLs using rustls.
+use crate::summary::{Phase, TlsHandshake};
+use hyper::client::connect::{Connected, Connection, HttpConnector};
+use hyper::service::Service;
+use hyper::Uri;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, HandshakeKind, RootCertStore, SignatureScheme};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// Extra CA certificates to trust in PEM format
+    pub ca_cert: Option<PathBuf>,
+    /// Client certificate chain in PEM format for mutual TLS
+    pub cert: Option<PathBuf>,
+    /// Private key for the client certificate in PEM format
+    pub key: Option<PathBuf>,
+    /// Don't verify the server certificate
+    pub insecure: bool,
+    /// Server name to use for SNI and certificate verification instead of the URL host
+    pub sni: Option<String>,
+    /// Only offer HTTP/2 when negotiating the protocol with ALPN
+    pub http2: bool,
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+impl TlsOptions {
+    pub fn client_config(&self) -> Result<ClientConfig, BoxError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = if self.insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        } else {
+            let mut roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            if let Some(ca_cert) = self.ca_cert.as_ref() {
+                for cert in load_certs(ca_cert)? {
+                    roots.add(cert)?;
+                }
+            }
+            builder.with_root_certificates(roots)
+        };
+        let mut config = match (self.cert.as_ref(), self.key.as_ref()) {
+            (Some(cert), Some(key)) => {
+                let certs = load_certs(cert)?;
+                let mut reader = BufReader::new(File::open(key)?);
+                let key = rustls_pemfile::private_key(&mut reader)?
+                    .ok_or_else(|| format!("No private key found in {}", key.display()))?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("Both a client certificate and key are needed for mutual TLS".into()),
+        };
+        config.alpn_protocols = if self.http2 {
+            vec![b"h2".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        Ok(config)
+    }
+}
+
+/// Used for `--insecure`, checks the handshake signatures are valid but accepts any certificate
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    pub id: usize,
+    /// When we started to open the connection
+    pub opened: Instant,
+    pub connect_time: Duration,
+    pub tls_time: Option<Duration>,
+    pub tls_handshake: Option<TlsHandshake>,
+    /// Total number of requests (HTTP/2 streams) which have been sent on this connection
+    streams: AtomicUsize,
+    /// Requests currently in flight on this connection
+    active_streams: AtomicUsize,
+    io: Mutex<IoState>,
+}
+
+#[derive(Debug)]
+struct IoState {
+    /// Whether we've written since the last read, a write after a read is a new request
+    writing: bool,
+    /// When the most recent request started being written to the connection
+    write_start: Instant,
+    /// Whether a request has been written whose response hasn't been fully read yet
+    in_use: bool,
+}
+
+impl ConnectionInfo {
+    fn new(id: usize, opened: Instant, connect_time: Duration) -> Self {
+        Self {
+            id,
+            opened,
+            connect_time,
+            tls_time: None,
+            tls_handshake: None,
+            streams: AtomicUsize::new(0),
+            active_streams: AtomicUsize::new(0),
+            io: Mutex::new(IoState {
+                writing: false,
+                write_start: opened + connect_time,
+                in_use: false,
+            }),
+        }
+    }
+
+    fn write_start(&self) -> Instant {
+        self.io.lock().unwrap().write_start
+    }
+
+    fn start_write(&self) {
+        let mut io = self.io.lock().unwrap();
+        if !io.writing {
+            io.writing = true;
+            io.write_start = Instant::now();
+            io.in_use = true;
+        }
+    }
+
+    fn finish_write(&self) {
+        self.io.lock().unwrap().writing = false;
+    }
+
+    /// Marks a request as in flight on this connection until the returned guard is dropped.
+    /// hyper doesn't tell us which connection a request is sent on until the response headers
+    /// arrive, so streams are counted from then
+    pub fn open_stream(self: &Arc<Self>) -> StreamGuard {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        let concurrent = self.active_streams.fetch_add(1, Ordering::Relaxed) + 1;
+        StreamGuard {
+            info: self.clone(),
+            concurrent,
+        }
+    }
+
+    pub fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    /// Whether the connection is sat in the pool with no requests in flight
+    fn is_idle(&self) -> bool {
+        !self.io.lock().unwrap().in_use && self.active_streams.load(Ordering::Relaxed) == 0
+    }
+
+    /// Whether the connection was opened for a request started at `start`
+    pub fn opened_for(&self, start: Instant) -> bool {
+        self.opened >= start
+    }
+
+    /// Works out the time spent in each phase of a request up to receiving the response headers
+    pub fn phases(&self, start: Instant, headers: Instant) -> BTreeMap<Phase, Duration> {
+        let mut phases = BTreeMap::new();
+        let write_start = self.write_start().max(start);
+        let mut setup = Duration::from_secs(0);
+        if self.opened_for(start) {
+            // The connection was opened for this request
+            phases.insert(Phase::Connect, self.connect_time);
+            setup += self.connect_time;
+            if let Some(tls) = self.tls_time {
+                phases.insert(Phase::Tls, tls);
+                setup += tls;
+            }
+        }
+        let waiting = write_start.duration_since(start);
+        phases.insert(
+            Phase::PoolWait,
+            waiting.checked_sub(setup).unwrap_or_default(),
+        );
+        phases.insert(
+            Phase::FirstByte,
+            headers.saturating_duration_since(write_start),
+        );
+        phases
+    }
+}
+
+/// Keeps a request counted as in flight on its connection
+pub struct StreamGuard {
+    info: Arc<ConnectionInfo>,
+    /// Number of streams in flight on the connection when this one was opened, including itself
+    pub concurrent: usize,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut io = self.info.io.lock().unwrap();
+        if self.info.active_streams.fetch_sub(1, Ordering::Relaxed) == 1 {
+            // The last response has been read so the connection goes back to being idle
+            io.in_use = false;
+        }
+    }
+}
+
+/// Attached to every response so we can find out about the connection it came from
+#[derive(Clone, Debug)]
+pub struct ConnectionHandle(pub Arc<ConnectionInfo>);
+
+#[derive(Clone)]
+pub struct TrackingConnector {
+    http: HttpConnector,
+    tls: TlsConnector,
+    sni: Option<ServerName<'static>>,
+    next_id: Arc<AtomicUsize>,
+    closed_by_server: Arc<AtomicUsize>,
+}
+
+impl TrackingConnector {
+    pub fn new() -> Self {
+        Self::with_tls(&TlsOptions::default()).expect("Default TLS config is invalid")
+    }
+
+    pub fn with_tls(options: &TlsOptions) -> Result<Self, BoxError> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let sni = match options.sni.as_ref() {
+            Some(sni) => Some(ServerName::try_from(sni.clone())?),
+            None => None,
+        };
+        Ok(Self {
+            http,
+            tls: TlsConnector::from(Arc::new(options.client_config()?)),
+            sni,
+            next_id: Default::default(),
+            closed_by_server: Default::default(),
+        })
+    }
+
+    /// Number of idle connections the server has closed so far
+    pub fn closed_by_server(&self) -> usize {
+        self.closed_by_server.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for TrackingConnector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service<Uri> for TrackingConnector {
+    type Response = TrackedStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        let tls = self.tls.clone();
+        let sni = self.sni.clone();
+        let closed_by_server = self.closed_by_server.clone();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        Box::pin(async move {
+            let is_https = uri.scheme_str() == Some("https");
+            let host = uri.host().unwrap_or_default().to_string();
+            let opened = Instant::now();
+            let stream = http.call(uri).await?;
+            let mut info = ConnectionInfo::new(id, opened, opened.elapsed());
+            let inner = if is_https {
+                let server_name = match sni {
+                    Some(sni) => sni,
+                    // IPv6 hosts are in brackets in URLs
+                    None => ServerName::try_from(host.trim_matches(|c| c == '[' || c == ']'))?
+                        .to_owned(),
+                };
+                let tls_start = Instant::now();
+                let stream = tls.connect(server_name, stream).await?;
+                info.tls_time = Some(tls_start.elapsed());
+                info.tls_handshake = match stream.get_ref().1.handshake_kind() {
+                    Some(HandshakeKind::Resumed) => Some(TlsHandshake::Resumed),
+                    _ => Some(TlsHandshake::Full),
+                };
+                MaybeTlsStream::Tls(Box::new(stream))
+            } else {
+                MaybeTlsStream::Plain(stream)
+            };
+            Ok(TrackedStream {
+                inner,
+                info: Arc::new(info),
+                closed: false,
+                closed_by_server,
+            })
+        })
+    }
+}
+
+enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+pub struct TrackedStream {
+    inner: MaybeTlsStream,
+    info: Arc<ConnectionInfo>,
+    /// Whether we've seen the connection reach EOF
+    closed: bool,
+    closed_by_server: Arc<AtomicUsize>,
+}
+
+impl Connection for TrackedStream {
+    fn connected(&self) -> Connected {
+        let connected = match &self.inner {
+            MaybeTlsStream::Plain(s) => s.connected(),
+            MaybeTlsStream::Tls(s) => {
+                let (tcp, tls) = s.get_ref();
+                if tls.alpn_protocol() == Some(b"h2") {
+                    tcp.connected().negotiated_h2()
+                } else {
+                    tcp.connected()
+                }
+            }
+        };
+        connected.extra(ConnectionHandle(self.info.clone()))
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_flush(cx),
+            Self::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            Self::Tls(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Plain(s) => s.is_write_vectored(),
+            Self::Tls(s) => s.is_write_vectored(),
+        }
+    }
+}
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let has_space = buf.remaining() > 0;
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            if buf.filled().len() > filled {
+                self.info.finish_write();
+            } else if has_space && !self.closed {
+                // Reading nothing into a buffer with space is EOF. If a response is being read
+                // the close just marks the end of its body
+                self.closed = true;
+                if self.info.is_idle() {
+                    self.closed_by_server.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        res
+    }
+}
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.info.start_write();
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.info.start_write();
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Client, Response, Server};
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn phases_for_new_and_reused_connections() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::from("hello")))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let client = Client::builder().build::<_, Body>(TrackingConnector::new());
+        let mut ids = vec![];
+        for i in 0..2 {
+            let start = Instant::now();
+            let res = client.get(url.parse().unwrap()).await.unwrap();
+            let conn = res.extensions().get::<ConnectionHandle>().unwrap().clone();
+            let phases = conn.0.phases(start, Instant::now());
+            hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+            assert_eq!(phases.contains_key(&Phase::Connect), i == 0);
+            assert!(phases.contains_key(&Phase::PoolWait));
+            assert!(phases.contains_key(&Phase::FirstByte));
+            ids.push(conn.0.id);
+        }
+        assert_eq!(ids[0], ids[1]);
+    }
+
+    #[tokio::test]
+    async fn tls_with_custom_ca() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let ca_path = std::env::temp_dir().join("murk_tls_with_custom_ca.pem");
+        std::fs::write(&ca_path, cert.cert.pem()).unwrap();
+
+        let key =
+            rustls::pki_types::PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.cert.der().clone()], key)
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "https://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let svc = service_fn(|_| async {
+                            Ok::<_, Infallible>(Response::new(Body::from("hello")))
+                        });
+                        let _ = hyper::server::conn::Http::new()
+                            .serve_connection(stream, svc)
+                            .await;
+                    }
+                });
+            }
+        });
+
+        // Not trusted without the CA
+        let client = Client::builder().build::<_, Body>(TrackingConnector::new());
+        assert!(client.get(url.parse().unwrap()).await.is_err());
+
+        for options in [
+            TlsOptions {
+                ca_cert: Some(ca_path),
+                ..Default::default()
+            },
+            TlsOptions {
+                insecure: true,
+                ..Default::default()
+            },
+        ] {
+            let connector = TrackingConnector::with_tls(&options).unwrap();
+            let client = Client::builder().build::<_, Body>(connector);
+            let start = Instant::now();
+            let res = client.get(url.parse().unwrap()).await.unwrap();
+            let conn = res.extensions().get::<ConnectionHandle>().unwrap().clone();
+            let phases = conn.0.phases(start, Instant::now());
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+            assert_eq!(body, "hello");
+            assert!(phases.contains_key(&Phase::Tls));
+            assert_eq!(conn.0.tls_handshake, Some(TlsHandshake::Full));
+        }
+    }
+
+    #[tokio::test]
+    async fn http2_streams_share_connection() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok::<_, Infallible>(Response::new(Body::from("hello")))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .http2_only(true)
+            .serve(make_svc);
+        let url: Uri = format!("http://{}/", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+
+        let client = Client::builder()
+            .http2_only(true)
+            .build::<_, Body>(TrackingConnector::new());
+        // Open the connection first so the concurrent requests don't race to make their own
+        client.get(url.clone()).await.unwrap();
+        let responses = futures::future::join_all((0..4).map(|_| client.get(url.clone()))).await;
+        let guards = responses
+            .iter()
+            .map(|res| {
+                let res = res.as_ref().unwrap();
+                res.extensions()
+                    .get::<ConnectionHandle>()
+                    .unwrap()
+                    .0
+                    .open_stream()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(guards.last().unwrap().concurrent, 4);
+        assert_eq!(guards[0].info.id, guards[3].info.id);
+        assert_eq!(guards[0].info.streams(), 4);
+    }
+
+    #[tokio::test]
+    async fn server_close_is_counted() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Closes connections shortly after responding, or straight away to end the body of
+        // requests to /eof
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 1024];
+                    let len = stream.read(&mut buf).await.unwrap();
+                    let response = if buf[..len].starts_with(b"GET /eof") {
+                        &b"HTTP/1.1 200 OK\r\n\r\nhello"[..]
+                    } else {
+                        &b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nhello"[..]
+                    };
+                    stream.write_all(response).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                });
+            }
+        });
+
+        let connector = TrackingConnector::new();
+        let client = Client::builder().build::<_, Body>(connector.clone());
+        for path in ["eof", "idle"] {
+            let url = format!("http://{}/{}", addr, path).parse().unwrap();
+            let res = client.get(url).await.unwrap();
+            let guard = res
+                .extensions()
+                .get::<ConnectionHandle>()
+                .unwrap()
+                .0
+                .open_stream();
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            std::mem::drop(guard);
+            assert_eq!(body, "hello");
+        }
+        // Give the pool a chance to notice the idle connection closing
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(connector.closed_by_server(), 1);
+    }
+}
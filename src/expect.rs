@@ -0,0 +1,165 @@
+//! Checks responses against the `expect` block given for an operation or request data in the
+//! specification. Each check has a name so failures can be counted separately in the summary.
+use crate::spec::Expect;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+use regex::Regex;
+use serde_json::Value;
+use std::time::Duration;
+
+/// An `Expect` block with the header names and regex parsed ready to check responses
+#[derive(Clone, Debug)]
+pub struct Expectations {
+    status: Vec<StatusCode>,
+    headers: Vec<(HeaderName, Option<HeaderValue>)>,
+    body_contains: Option<String>,
+    body_matches: Option<Regex>,
+    json: Vec<(String, Value)>,
+    max_latency: Option<Duration>,
+}
+
+impl Expectations {
+    pub fn new(expect: &Expect) -> Result<Self, String> {
+        let status = expect
+            .status
+            .iter()
+            .map(|s| StatusCode::from_u16(*s).map_err(|e| format!("Invalid status {}: {}", s, e)))
+            .collect::<Result<_, _>>()?;
+        let mut headers = vec![];
+        for (name, value) in &expect.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+            let value = match value {
+                Some(v) => Some(
+                    HeaderValue::from_str(v)
+                        .map_err(|e| format!("Invalid header value {}: {}", v, e))?,
+                ),
+                None => None,
+            };
+            headers.push((name, value));
+        }
+        let body_matches = match expect.body_matches.as_ref() {
+            Some(re) => Some(Regex::new(re).map_err(|e| e.to_string())?),
+            None => None,
+        };
+        Ok(Self {
+            status,
+            headers,
+            body_contains: expect.body_contains.clone(),
+            body_matches,
+            json: expect
+                .json
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            max_latency: expect.max_latency_ms.map(Duration::from_millis),
+        })
+    }
+
+    /// Returns the names of the checks the response failed
+    pub fn check(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+        latency: Duration,
+    ) -> Vec<String> {
+        let mut failed = vec![];
+        let status_ok = if self.status.is_empty() {
+            status.is_success()
+        } else {
+            self.status.contains(&status)
+        };
+        if !status_ok {
+            failed.push("status".to_string());
+        }
+        for (name, value) in &self.headers {
+            let ok = match (headers.get(name), value) {
+                (Some(actual), Some(expected)) => actual == expected,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if !ok {
+                failed.push(format!("header {}", name));
+            }
+        }
+        if self.body_contains.is_some() || self.body_matches.is_some() {
+            let text = String::from_utf8_lossy(body);
+            if let Some(contains) = self.body_contains.as_ref() {
+                if !text.contains(contains.as_str()) {
+                    failed.push("body contains".to_string());
+                }
+            }
+            if let Some(re) = self.body_matches.as_ref() {
+                if !re.is_match(&text) {
+                    failed.push("body matches".to_string());
+                }
+            }
+        }
+        if !self.json.is_empty() {
+            let json = serde_json::from_slice::<Value>(body).ok();
+            for (pointer, expected) in &self.json {
+                let actual = json.as_ref().and_then(|j| j.pointer(pointer));
+                if actual != Some(expected) {
+                    failed.push(format!("json {}", pointer));
+                }
+            }
+        }
+        if let Some(max) = self.max_latency {
+            if latency > max {
+                failed.push("max latency".to_string());
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_response() {
+        let expect: Expect = serde_yaml::from_str(
+            r#"
+            status: [200, 201]
+            headers:
+              content-type: application/json
+              x-request-id: ~
+            bodyMatches: '"id":\s*\d+'
+            json:
+              /status: done
+            maxLatencyMs: 100
+        "#,
+        )
+        .unwrap();
+        let expect = Expectations::new(&expect).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        headers.insert("x-request-id", HeaderValue::from_static("abc"));
+        let body = br#"{"id": 4, "status": "done"}"#;
+
+        let ok = expect.check(StatusCode::OK, &headers, body, Duration::from_millis(5));
+        assert!(ok.is_empty());
+
+        headers.remove("x-request-id");
+        let failed = expect.check(
+            StatusCode::OK,
+            &headers,
+            br#"{"status": "failed"}"#,
+            Duration::from_millis(500),
+        );
+        assert_eq!(
+            failed,
+            vec![
+                "header x-request-id",
+                "body matches",
+                "json /status",
+                "max latency"
+            ]
+        );
+
+        let failed = expect.check(StatusCode::ACCEPTED, &headers, body, Duration::default());
+        assert_eq!(failed[0], "status");
+    }
+}
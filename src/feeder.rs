@@ -0,0 +1,231 @@
+//! Feeders supply rows of test data from CSV or JSONL files, for example a list of user IDs and
+//! their matching queries. Each column becomes a variable named after the feeder and the column,
+//! e.g. `{{users.id}}`, which can be used in any template. A request takes one row from each
+//! feeder it uses so values from the same row stay together, and a scenario takes one row for the
+//! whole run.
+use crate::spec::{self, FeederFormat, FeederStrategy};
+use crate::template::Variables;
+use rand::Rng;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+type Row = Vec<(String, String)>;
+
+pub struct Feeder {
+    pub name: String,
+    /// Every column found in the file
+    columns: Vec<String>,
+    rows: Vec<Row>,
+    strategy: FeederStrategy,
+    /// Next row for the sequential and circular strategies, shared between all users
+    cursor: AtomicUsize,
+}
+
+fn read_csv(data: &str) -> Result<(Vec<String>, Vec<Row>), String> {
+    let mut reader = csv::Reader::from_reader(data.as_bytes());
+    let columns = reader
+        .headers()
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<_>>();
+    let mut rows = vec![];
+    for record in reader.records() {
+        let record = record.map_err(|e| e.to_string())?;
+        let row = columns
+            .iter()
+            .cloned()
+            .zip(record.iter().map(|v| v.to_string()))
+            .collect();
+        rows.push(row);
+    }
+    Ok((columns, rows))
+}
+
+fn read_jsonl(data: &str) -> Result<(Vec<String>, Vec<Row>), String> {
+    let mut columns = vec![];
+    let mut rows = vec![];
+    for (i, line) in data
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+    {
+        let object = match serde_json::from_str::<Value>(line) {
+            Ok(Value::Object(o)) => o,
+            Ok(_) => return Err(format!("Line {} isn't a JSON object", i + 1)),
+            Err(e) => return Err(format!("Line {}: {}", i + 1, e)),
+        };
+        let mut row = vec![];
+        for (column, value) in object {
+            if !columns.contains(&column) {
+                columns.push(column.clone());
+            }
+            let value = match value {
+                Value::String(s) => s,
+                v => v.to_string(),
+            };
+            row.push((column, value));
+        }
+        rows.push(row);
+    }
+    Ok((columns, rows))
+}
+
+fn guess_format(path: &Path) -> Option<FeederFormat> {
+    match path.extension()?.to_str()? {
+        "csv" => Some(FeederFormat::Csv),
+        "jsonl" | "ndjson" => Some(FeederFormat::Jsonl),
+        _ => None,
+    }
+}
+
+impl Feeder {
+    pub fn load(name: &str, feeder: &spec::Feeder) -> Result<Self, String> {
+        let path = feeder.path.display();
+        let format = feeder
+            .format
+            .or_else(|| guess_format(&feeder.path))
+            .ok_or_else(|| format!("Unknown format for {}, set it to csv or jsonl", path))?;
+        let data = fs::read_to_string(&feeder.path)
+            .map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+        let (columns, rows) = match format {
+            FeederFormat::Csv => read_csv(&data),
+            FeederFormat::Jsonl => read_jsonl(&data),
+        }
+        .map_err(|e| format!("Invalid data in {}: {}", path, e))?;
+        if rows.is_empty() {
+            return Err(format!("No rows in {}", path));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            columns,
+            rows,
+            strategy: feeder.strategy,
+            cursor: AtomicUsize::new(0),
+        })
+    }
+
+    /// Whether the variable is one of this feeder's columns
+    pub fn provides(&self, var: &str) -> bool {
+        var.strip_prefix(self.name.as_str())
+            .and_then(|v| v.strip_prefix('.'))
+            .is_some_and(|column| self.columns.iter().any(|c| c == column))
+    }
+
+    /// Goes back to the first row, so each level of a ramp starts from the beginning of the file
+    pub fn reset(&self) {
+        self.cursor.store(0, Ordering::Relaxed);
+    }
+
+    /// Sets the variables from the next row, returns false once a sequential feeder has used all
+    /// its rows
+    pub fn feed(&self, vars: &mut Variables) -> bool {
+        let index = match self.strategy {
+            FeederStrategy::Sequential => {
+                let index = self.cursor.fetch_add(1, Ordering::Relaxed);
+                if index == self.rows.len() {
+                    eprintln!("Feeder {} has used all its rows", self.name);
+                }
+                if index >= self.rows.len() {
+                    return false;
+                }
+                index
+            }
+            FeederStrategy::Circular => {
+                self.cursor.fetch_add(1, Ordering::Relaxed) % self.rows.len()
+            }
+            FeederStrategy::Random => rand::thread_rng().gen_range(0..self.rows.len()),
+        };
+        let row = &self.rows[index];
+        for column in &self.columns {
+            let var = format!("{}.{}", self.name, column);
+            // JSONL rows can be missing columns, don't leave the value from the last row
+            match row.iter().find(|(c, _)| c == column) {
+                Some((_, value)) => vars.insert(var, value.clone()),
+                None => vars.remove(&var),
+            };
+        }
+        true
+    }
+}
+
+/// The feeders which provide any of the variables
+pub fn feeders_for<'a>(
+    feeders: &[Arc<Feeder>],
+    vars: impl IntoIterator<Item = &'a str>,
+) -> Vec<Arc<Feeder>> {
+    let vars = vars.into_iter().collect::<Vec<_>>();
+    feeders
+        .iter()
+        .filter(|f| vars.iter().any(|v| f.provides(v)))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn feeder(data: &str, format: FeederFormat, strategy: FeederStrategy) -> Feeder {
+        let (columns, rows) = match format {
+            FeederFormat::Csv => read_csv(data),
+            FeederFormat::Jsonl => read_jsonl(data),
+        }
+        .unwrap();
+        Feeder {
+            name: "users".to_string(),
+            columns,
+            rows,
+            strategy,
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn strategies() {
+        let csv = "id,query\n1,a b\n2,\"c,d\"\n";
+        let users = feeder(csv, FeederFormat::Csv, FeederStrategy::Sequential);
+        assert!(users.provides("users.query"));
+        assert!(!users.provides("users.name"));
+        assert!(!users.provides("query"));
+        let mut vars = Variables::new();
+        assert!(users.feed(&mut vars));
+        assert_eq!(vars["users.id"], "1");
+        assert_eq!(vars["users.query"], "a b");
+        assert!(users.feed(&mut vars));
+        assert_eq!(vars["users.query"], "c,d");
+        assert!(!users.feed(&mut vars));
+        users.reset();
+        assert!(users.feed(&mut vars));
+        assert_eq!(vars["users.id"], "1");
+
+        let users = feeder(csv, FeederFormat::Csv, FeederStrategy::Circular);
+        let ids = (0..3)
+            .map(|_| {
+                users.feed(&mut vars);
+                vars["users.id"].clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["1", "2", "1"]);
+
+        let jsonl = "{\"id\": 5, \"name\": \"x\"}\n\n{\"id\": 6}\n";
+        let users = feeder(jsonl, FeederFormat::Jsonl, FeederStrategy::Random);
+        assert!(users.provides("users.name"));
+        for _ in 0..5 {
+            assert!(users.feed(&mut vars));
+            match vars["users.id"].as_str() {
+                "5" => assert_eq!(vars["users.name"], "x"),
+                id => assert!(id == "6" && !vars.contains_key("users.name")),
+            }
+        }
+        assert!(read_jsonl("[1]").is_err());
+        assert_eq!(
+            guess_format(&PathBuf::from("a/b.ndjson")),
+            Some(FeederFormat::Jsonl)
+        );
+    }
+}
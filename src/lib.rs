@@ -1,25 +1,47 @@
+use crate::connector::*;
 use crate::request::*;
 use crate::scripting::*;
 use crate::spec::*;
+use crate::stage::Stage;
 use crate::summary::*;
+use crate::template::Variables;
 use bytes::{Buf, BytesMut};
 use futures::stream::{FuturesUnordered, StreamExt};
+use hdrhistogram::serialization::interval_log::{IntervalLogWriterBuilder, Tag};
+use hdrhistogram::serialization::V2DeflateSerializer;
 use humantime::Duration;
 use hyper::body::HttpBody;
-use hyper::Client;
+use hyper::{Body, Client, Request, Response};
 use quanta::Clock;
-use std::fs;
-use std::path::PathBuf;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration as StdDuration;
+use std::time::{Duration as StdDuration, SystemTime};
 pub use structopt::StructOpt;
-use tokio::sync::mpsc;
-use tokio::time::{sleep, timeout};
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, sleep, sleep_until, timeout, Instant, Interval};
 
+pub mod connector;
+pub mod expect;
+pub mod feeder;
 pub mod request;
+pub mod scenario;
+pub mod schema;
 pub mod scripting;
 pub mod spec;
+pub mod stage;
 pub mod summary;
+pub mod template;
 
 #[derive(Clone, Debug, StructOpt)]
 pub struct Opt {
@@ -30,15 +52,25 @@ pub struct Opt {
     /// Number of jobs (worker threads) to use in the scheduler
     #[structopt(short = "j", long = "n-jobs")]
     jobs: Option<usize>,
-    /// Number of HTTP connections to use concurrently
+    /// Number of virtual users sending requests concurrently. Each user has its own HTTP
+    /// connection unless `--connection-mode` says otherwise
     #[structopt(short = "c", long = "connections")]
     connections: Option<usize>,
     /// Timeout for a request. If a request takes longer than this to respond it will be cancelled
     #[structopt(short = "t", long = "timeout")]
     timeout: Duration,
-    /// Duration to run the loadtest for
+    /// Duration to run the loadtest for at each level, not needed when running stages
     #[structopt(short = "d", long = "duration")]
-    duration: Duration,
+    duration: Option<Duration>,
+    /// Run at full load for this long at the start of each level before measuring. Requests
+    /// finishing in the warm-up aren't included in the results, so cold caches and opening
+    /// connections don't skew them. It can't be used with stages, use a first stage to warm up
+    /// instead
+    #[structopt(long = "warmup")]
+    warmup: Option<Duration>,
+    /// Time to wait between levels of `--ramp` for the server to settle
+    #[structopt(long = "cooldown", default_value = "2s")]
+    cooldown: Duration,
     /// Path to a configuration file
     #[structopt(long = "config")]
     config: Option<PathBuf>,
@@ -50,6 +82,99 @@ pub struct Opt {
     /// different options for `--connections`
     #[structopt(long = "ramp")]
     ramp: Option<Vec<usize>>,
+    /// Stages of a load profile to run instead of fixed levels of connections. Each stage is
+    /// `from..to:duration`, or `to:duration` to start from where the previous stage ended, and
+    /// users are added or removed steadily through it e.g. `0..200:60s 200:5m 0:30s`. Replaces
+    /// any stages in the config file. With `--rate` the rate is reached at the peak number of
+    /// users
+    #[structopt(long = "stages")]
+    stages: Option<Vec<Stage>>,
+    /// Constant throughput to aim for in requests per second, shared across all connections. When
+    /// set requests are sent at fixed intended start times and latency is measured from then,
+    /// correcting for coordinated omission
+    #[structopt(long = "rate")]
+    rate: Option<f64>,
+    /// Write the results for every connection level to this file as JSON
+    #[structopt(long = "output")]
+    output: Option<PathBuf>,
+    /// Write the latency and custom histograms to this file in the HdrHistogram interval log
+    /// format. The histograms cover each `--report-interval`, or each second if there isn't one,
+    /// and are tagged with their name and connection level, or stage number when running stages
+    #[structopt(long = "hdr-log")]
+    hdr_log: Option<PathBuf>,
+    /// Print throughput, errors and latency quantiles for each interval of this length while the
+    /// test is running
+    #[structopt(long = "report-interval")]
+    report_interval: Option<Duration>,
+    /// Keep a separate latency histogram for each response status code
+    #[structopt(long = "status-latency")]
+    status_latency: bool,
+    /// Trust the CA certificates in this PEM file when connecting to https endpoints
+    #[structopt(long = "cacert")]
+    cacert: Option<PathBuf>,
+    /// Client certificate in PEM format to present to the server, needs `--key`
+    #[structopt(long = "cert", requires = "key")]
+    cert: Option<PathBuf>,
+    /// Private key in PEM format for the client certificate
+    #[structopt(long = "key", requires = "cert")]
+    key: Option<PathBuf>,
+    /// Don't verify the server certificate
+    #[structopt(short = "k", long = "insecure")]
+    insecure: bool,
+    /// Server name to send in the TLS handshake and verify the certificate against, instead of
+    /// the host in the URL
+    #[structopt(long = "sni")]
+    sni: Option<String>,
+    /// Use HTTP/2. Plain http endpoints are sent HTTP/2 with prior knowledge (h2c) and https
+    /// endpoints negotiate it with ALPN
+    #[structopt(long = "http2")]
+    http2: bool,
+    /// Number of concurrent HTTP/2 streams to run on each connection, only used with `--http2`.
+    /// The total concurrency is the number of connections multiplied by this
+    #[structopt(long = "streams", default_value = "1")]
+    streams: usize,
+    /// How connections are managed. `per-user` keeps a connection open for each user, `shared`
+    /// has all users share a pool of `--pool-size` connections and `per-request` opens a new
+    /// connection for every request
+    #[structopt(
+        long = "connection-mode",
+        default_value = "per-user",
+        possible_values = &["per-user", "shared", "per-request"]
+    )]
+    connection_mode: ConnectionMode,
+    /// Number of connections in the shared pool, defaults to the number of users
+    #[structopt(long = "pool-size")]
+    pool_size: Option<usize>,
+    /// How users pick which request to send next. `weighted` makes a weighted random pick for
+    /// every request, `sequential` goes through the requests in order and `shuffle` goes through
+    /// a new random order on every pass. Scenarios are always picked by weight so only `weighted`
+    /// can be used with them
+    #[structopt(
+        long = "selection",
+        default_value = "weighted",
+        possible_values = &["weighted", "sequential", "shuffle"]
+    )]
+    selection: SelectionMode,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionMode {
+    PerUser,
+    Shared,
+    PerRequest,
+}
+
+impl FromStr for ConnectionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-user" => Ok(Self::PerUser),
+            "shared" => Ok(Self::Shared),
+            "per-request" => Ok(Self::PerRequest),
+            _ => Err(format!("Unknown connection mode: {}", s)),
+        }
+    }
 }
 
 impl Opt {
@@ -61,9 +186,51 @@ impl Opt {
         }
     }
 
+    /// Concurrent requests sharing each connection
+    pub fn streams(&self) -> usize {
+        if self.http2 {
+            self.streams.max(1)
+        } else {
+            1
+        }
+    }
+
+    pub fn duration(&self) -> StdDuration {
+        self.duration.map(|d| *d).unwrap_or_default()
+    }
+
+    pub fn warmup(&self) -> StdDuration {
+        self.warmup.map(|d| *d).unwrap_or_default()
+    }
+
+    /// Length of the slices to split the results into for the histogram log, if writing one
+    pub fn hdr_period(&self) -> Option<StdDuration> {
+        self.hdr_log.as_ref().map(|_| HDR_LOG_PERIOD)
+    }
+
     pub fn jobs(&self) -> usize {
         self.jobs.unwrap_or_else(num_cpus::get)
     }
+
+    /// The interval between intended request start times for a single user when running at a
+    /// constant rate
+    pub fn request_interval(&self, users: usize) -> Option<StdDuration> {
+        self.rate
+            .filter(|r| *r > 0.0)
+            .map(|r| StdDuration::from_secs_f64(users as f64 / r))
+    }
+
+    fn client(&self, connector: TrackingConnector, users: usize) -> Client<TrackingConnector> {
+        let idle = match self.connection_mode {
+            ConnectionMode::PerUser => 1,
+            ConnectionMode::Shared => self.pool_size.unwrap_or(users),
+            ConnectionMode::PerRequest => 0,
+        };
+        Client::builder()
+            .http2_only(self.http2)
+            .pool_max_idle_per_host(idle)
+            .build::<_, Body>(connector)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -71,93 +238,624 @@ pub enum RunError {
     ChannelClosed,
 }
 
-async fn run_user(
+/// Length of the slices in the histogram log when there's no report interval
+const HDR_LOG_PERIOD: StdDuration = StdDuration::from_secs(1);
+
+/// How often the number of users is updated while running stages
+const STAGE_TICK: StdDuration = StdDuration::from_millis(100);
+
+/// Sends stats to the collector, tagging them with the current stage when running stages
+#[derive(Clone)]
+struct StatsSender {
     tx: mpsc::UnboundedSender<RequestStats>,
-    store: Arc<RequestStore>,
-    opt: Arc<Opt>,
+    stage: Option<Arc<AtomicUsize>>,
+    /// End of the warm-up, stats before this are dropped
+    measure_from: Option<Instant>,
+}
+
+impl StatsSender {
+    fn send(&self, mut stats: RequestStats) -> Result<(), RunError> {
+        if self.measure_from.is_some_and(|t| Instant::now() < t) {
+            return Ok(());
+        }
+        stats.stage = self.stage.as_ref().map(|s| s.load(Ordering::Relaxed));
+        self.tx.send(stats).map_err(|_| RunError::ChannelClosed)
+    }
+}
+
+/// Sends a request, first waiting for a connection from the shared pool if there is one. The
+/// returned permit should be held until the response has been read
+async fn send_request(
+    client: &Client<TrackingConnector>,
+    pool: Option<&Arc<Semaphore>>,
+    request: Request<Body>,
+) -> hyper::Result<(Response<Body>, Option<OwnedSemaphorePermit>)> {
+    let permit = match pool {
+        Some(pool) => pool.clone().acquire_owned().await.ok(),
+        None => None,
+    };
+    let response = client.request(request).await?;
+    Ok((response, permit))
+}
+
+/// The state belonging to a single virtual user
+struct User {
+    id: usize,
+    client: Client<TrackingConnector>,
+    /// Semaphore limiting requests in flight to the size of the shared connection pool
+    pool: Option<Arc<Semaphore>>,
+    hook: Option<RequestHook>,
+    /// Keep response headers for the script's `handle_response` function, scenario steps always
+    /// keep them
+    capture_headers: bool,
+    /// Number of connections at the current level, or the peak number when running stages
     connections: usize,
-) -> Result<(), RunError> {
-    let requests = store.get_requests(store.len());
-    let clock = Clock::new();
-    let client = Client::new();
-    let timeout_dur = *opt.timeout;
-    let delay = sleep(*opt.duration);
-    tokio::pin!(delay);
-    for req in requests.iter().cycle() {
-        let start = clock.now();
+    /// When running stages the user stops once this is dropped instead of at the end of the
+    /// test duration
+    stop: Option<oneshot::Receiver<()>>,
+    /// Set to true once all the users for the level have been spawned so they start together
+    start: watch::Receiver<bool>,
+}
+
+/// Sends requests and times the responses
+struct UserClient {
+    client: Client<TrackingConnector>,
+    pool: Option<Arc<Semaphore>>,
+    clock: Clock,
+    timeout: StdDuration,
+    connections: usize,
+    capture_headers: bool,
+}
+
+impl UserClient {
+    async fn execute(
+        &self,
+        req: &RequestBuilder,
+        info: Arc<RequestInfo>,
+        wait_time: Option<StdDuration>,
+    ) -> RequestStats {
+        let start = self.clock.now();
+        let request_start = Instant::now();
+        let start_time = Some(SystemTime::now());
+        let (request, upload) = req.timed_request();
+        let res = timeout(
+            self.timeout,
+            send_request(&self.client, self.pool.as_ref(), request),
+        )
+        .await;
+        let (mut s, _permit) = match res {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                return RequestStats {
+                    start_time,
+                    wait_time,
+                    error: Some(RequestError::from(&e)),
+                    request: Some(info),
+                    connections: self.connections,
+                    ..Default::default()
+                };
+            }
+            Err(_) => {
+                return RequestStats {
+                    start_time,
+                    wait_time,
+                    timeout: true,
+                    request: Some(info),
+                    connections: self.connections,
+                    ..Default::default()
+                };
+            }
+        };
+        let headers = Instant::now();
+        let conn = s.extensions().get::<ConnectionHandle>().cloned();
+        let mut phases = BTreeMap::new();
+        let mut tls_handshake = None;
+        let mut stream = None;
+        let mut connection_id = None;
+        let mut new_connection = None;
+        if let Some(conn) = conn {
+            connection_id = Some(conn.0.id);
+            new_connection = Some(conn.0.opened_for(request_start));
+            phases = conn.0.phases(request_start, headers);
+            if conn.0.opened_for(request_start) {
+                tls_handshake = conn.0.tls_handshake;
+            }
+            stream = Some(conn.0.open_stream());
+        }
+        let mut bytes_read = 0;
+        let mut buf = BytesMut::new();
+        let mut error = None;
+        while let Some(body) = s.body_mut().data().await {
+            match body {
+                Ok(body) => {
+                    bytes_read += body.len();
+                    buf.extend_from_slice(body.chunk());
+                }
+                Err(_) => {
+                    error = Some(RequestError::BodyRead);
+                    break;
+                }
+            }
+        }
+        let end = self.clock.now();
+        let request_time = Some(end.duration_since(start));
+        let post_upload_time = upload.finished().map(|t| t.elapsed());
+        let body = buf.freeze();
+        let assertion_failures = match (req.expectations(), request_time) {
+            (Some(expect), Some(latency)) if error.is_none() => {
+                Some(expect.check(s.status(), s.headers(), &body, latency))
+            }
+            _ => None,
+        };
+        phases.insert(Phase::Download, headers.elapsed());
+        RequestStats {
+            start_time,
+            status: Some(s.status()),
+            headers: self.capture_headers.then(|| s.headers().clone()),
+            request_time,
+            wait_time,
+            post_upload_time,
+            error,
+            assertion_failures,
+            phases,
+            tls_handshake,
+            request: Some(info),
+            connection_id,
+            new_connection,
+            concurrent_streams: stream.map(|s| s.concurrent),
+            body: Some(body),
+            bytes_read: Some(bytes_read),
+            bytes_written: Some(req.body_len()),
+            connections: self.connections,
+            ..Default::default()
+        }
+    }
+}
+
+/// Paces a user's requests and stops them once the test duration is up
+struct RequestRunner {
+    client: UserClient,
+    /// Time between request starts when running at a constant rate
+    interval: Option<StdDuration>,
+    intended_start: Instant,
+    end: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl RequestRunner {
+    /// Sends the request, returning `None` if the test finished first
+    async fn send(&mut self, req: &RequestBuilder, info: Arc<RequestInfo>) -> Option<RequestStats> {
+        // In constant throughput mode wait for the intended start time. If we're running behind
+        // this returns immediately and the time we've been waiting is added to the latency
+        let wait_time = if let Some(interval) = self.interval {
+            tokio::select! {
+                biased;
+                _ = &mut self.end => {
+                    return None;
+                }
+                _ = sleep_until(self.intended_start) => {}
+            }
+            let wait_time = self.intended_start.elapsed();
+            self.intended_start += interval;
+            Some(wait_time)
+        } else {
+            None
+        };
         tokio::select! {
             biased;
-            res = timeout(timeout_dur, client.request(req.request())) => {
-                match res {
-                    Ok(Ok(mut s)) => {
-                        let mut bytes_read = 0;
-                        let mut buf = BytesMut::new();
-                        while let Some(Ok(body)) = s.body_mut().data().await {
-                            bytes_read += body.len();
-                            buf.extend_from_slice(body.chunk());
-                        }
-                        let end = clock.now();
-                        let request_time = Some(end.duration_since(start));
-                        tx.send(RequestStats {
-                            status: Some(s.status()),
-                            request_time,
-                            timeout: false,
-                            body: Some(buf.freeze()),
-                            bytes_read: Some(bytes_read),
-                            bytes_written: Some(req.body_len()),
-                            connections,
-                        }).map_err(|_| RunError::ChannelClosed)?;
-                    },
-                    Ok(Err(_)) => {
-                        tx.send(RequestStats {
-                            status: None,
-                            request_time: None,
-                            timeout: false,
-                            body: None,
-                            bytes_read: None,
-                            bytes_written: None,
-                            connections,
-                        }).map_err(|_| RunError::ChannelClosed)?;
-                    },
-                    Err(_) => {
-                        tx.send(RequestStats {
-                            status: None,
-                            request_time: None,
-                            timeout: true,
-                            body: None,
-                            bytes_read: None,
-                            bytes_written: None,
-                            connections,
-                        }).map_err(|_| RunError::ChannelClosed)?;
-                    },
+            stats = self.client.execute(req, info, wait_time) => Some(stats),
+            _ = &mut self.end => None,
+        }
+    }
+
+    /// Waits for the given time, returning false if the test finished first
+    async fn pause(&mut self, duration: StdDuration) -> bool {
+        let finished = tokio::select! {
+            biased;
+            _ = &mut self.end => false,
+            _ = sleep(duration) => true,
+        };
+        // The pause is meant to happen so the next request shouldn't count it as time spent
+        // waiting to be sent, or try to catch up on the requests it skipped
+        self.intended_start = self.intended_start.max(Instant::now());
+        finished
+    }
+}
+
+async fn run_user(
+    mut tx: StatsSender,
+    store: Arc<RequestStore>,
+    opt: Arc<Opt>,
+    mut user: User,
+) -> Result<(), RunError> {
+    while !*user.start.borrow() {
+        if user.start.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+    if opt.warmup.is_some() {
+        tx.measure_from = Some(Instant::now() + opt.warmup());
+    }
+    let users = user.connections * opt.streams();
+    let interval = opt.request_interval(users);
+    // Spread the users' start times across the interval so they don't all fire at once
+    let offset = interval
+        .map(|i| i.mul_f64((user.id % users.max(1)) as f64 / users.max(1) as f64))
+        .unwrap_or_default();
+    let mut runner = RequestRunner {
+        client: UserClient {
+            client: user.client,
+            pool: user.pool,
+            clock: Clock::new(),
+            timeout: *opt.timeout,
+            connections: user.connections,
+            capture_headers: user.capture_headers,
+        },
+        interval,
+        intended_start: Instant::now() + offset,
+        end: match user.stop {
+            Some(stop) => Box::pin(async move {
+                let _ = stop.await;
+            }),
+            None => Box::pin(sleep(opt.warmup() + opt.duration())),
+        },
+    };
+    if store.scenarios.is_empty() {
+        run_requests(&tx, &store, opt.selection, user.id, user.hook, &mut runner).await
+    } else {
+        run_scenarios(&tx, &store, &mut runner).await
+    }
+}
+
+async fn run_requests(
+    tx: &StatsSender,
+    store: &RequestStore,
+    selection: SelectionMode,
+    id: usize,
+    hook: Option<RequestHook>,
+    runner: &mut RequestRunner,
+) -> Result<(), RunError> {
+    let mut vars = Variables::new();
+    for mut index in store.selector(selection) {
+        let mut change = None;
+        if let Some(hook) = hook.as_ref() {
+            change = hook.make_request(id).await;
+            if let Some(base) = change.as_ref().and_then(|c| c.index) {
+                if base < store.requests.len() {
+                    index = base;
+                } else {
+                    eprintln!("Script asked for missing request {}", base);
+                    change = None;
                 }
             }
-            _ = &mut delay => {
+        }
+        if !store.requests[index].feed(&mut vars) {
+            break;
+        }
+        let mut req = match store.requests[index].render(&vars) {
+            Ok(req) => req,
+            Err(e) => {
+                // The same template would fail every time
+                eprintln!(
+                    "Couldn't fill in templates for {}: {}",
+                    store.info(index).name,
+                    e
+                );
                 break;
             }
+        };
+        if let Some(change) = change {
+            match change.apply(&req) {
+                Ok(changed) => req = Cow::Owned(changed),
+                Err(e) => eprintln!("Invalid request from script: {}", e),
+            }
+        }
+        let info = match &req {
+            Cow::Borrowed(_) => store.info(index),
+            Cow::Owned(req) => Arc::new(req.info(store.info(index).name.clone())),
+        };
+        match runner.send(&req, info).await {
+            Some(stats) => tx.send(stats)?,
+            None => break,
         }
     }
     Ok(())
 }
 
+async fn run_scenarios(
+    tx: &StatsSender,
+    store: &RequestStore,
+    runner: &mut RequestRunner,
+) -> Result<(), RunError> {
+    // Steps need the headers to extract variables and check when polling is finished
+    runner.client.capture_headers = true;
+    let weights = WeightedIndex::new(store.scenarios.iter().map(|s| s.weight)).ok();
+    loop {
+        let scenario = match weights.as_ref() {
+            Some(weights) => &store.scenarios[weights.sample(&mut rand::thread_rng())],
+            None => store.scenarios.choose(&mut rand::thread_rng()).unwrap(),
+        };
+        let mut vars = Variables::new();
+        if !scenario.feeders.iter().all(|f| f.feed(&mut vars)) {
+            return Ok(());
+        }
+        let start = Instant::now();
+        for (i, step) in scenario.steps.iter().enumerate() {
+            let failed = ScenarioResult {
+                name: scenario.name.clone(),
+                time: start.elapsed(),
+                completed: false,
+            };
+            let req = match step.build(&vars) {
+                Ok(req) => req,
+                Err(e) => {
+                    eprintln!("Couldn't create request for {}: {}", step.name, e);
+                    // Nothing was sent so this only counts the scenario as failed
+                    tx.send(RequestStats {
+                        scenario: Some(failed),
+                        ..Default::default()
+                    })?;
+                    break;
+                }
+            };
+            let info = Arc::new(step.info(&req));
+            let mut polls = 0;
+            let mut stats = loop {
+                polls += 1;
+                let stats = match runner.send(&req, info.clone()).await {
+                    Some(stats) => stats,
+                    None => return Ok(()),
+                };
+                if polls < step.max_polls && stats.succeeded() && !step.finished_polling(&stats) {
+                    tx.send(stats)?;
+                    if !runner.pause(step.poll_interval).await {
+                        return Ok(());
+                    }
+                } else {
+                    break stats;
+                }
+            };
+            let mut failures = vec![];
+            if stats.succeeded() {
+                if !step.finished_polling(&stats) {
+                    failures.push("until".to_string());
+                }
+                for var in step.extract(&stats, &mut vars) {
+                    failures.push(format!("extract {}", var));
+                }
+            }
+            if !failures.is_empty() {
+                stats
+                    .assertion_failures
+                    .get_or_insert_with(Vec::new)
+                    .append(&mut failures);
+            }
+            let completed = stats.succeeded();
+            if !completed || i + 1 == scenario.steps.len() {
+                stats.scenario = Some(ScenarioResult {
+                    time: start.elapsed(),
+                    completed,
+                    ..failed
+                });
+            }
+            tx.send(stats)?;
+            if !completed {
+                break;
+            }
+        }
+    }
+}
+
+fn handle_script_event(summary: &mut Summary, event: ScriptEvents) {
+    match event {
+        ScriptEvents::RegisterHistogram {
+            name,
+            min,
+            max,
+            accuracy,
+        } => {
+            if let Err(e) = summary.register_custom_histogram(name, min, max, accuracy.unwrap_or(3))
+            {
+                eprintln!("{}", e);
+            }
+        }
+        ScriptEvents::UpdateHistogram { name, value } => {
+            if !summary.record_custom(&name, value) {
+                eprintln!("Script recorded to unregistered histogram: {}", name);
+            }
+        }
+        ScriptEvents::Flushed => {}
+    }
+}
+
+async fn next_script_event(
+    events: Option<&flume::Receiver<ScriptEvents>>,
+) -> Result<ScriptEvents, flume::RecvError> {
+    match events {
+        Some(events) => events.recv_async().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn next_report(ticker: Option<&mut Interval>) -> Instant {
+    match ticker {
+        Some(ticker) => ticker.tick().await,
+        None => futures::future::pending().await,
+    }
+}
+
+fn print_interval(start: Instant, interval_start: Instant, summary: &Summary) {
+    let elapsed = interval_start.elapsed().as_secs_f64();
+    let requests = summary.success + summary.failure + summary.timeout + summary.assertion_failed;
+    println!(
+        "[{:.1}s] {:.1} req/s, {} errors, {} failed assertions, p50: {}ms, p99: {}ms",
+        start.elapsed().as_secs_f64(),
+        requests as f64 / elapsed,
+        summary.failure + summary.timeout,
+        summary.assertion_failed,
+        summary.histogram.value_at_quantile(0.5),
+        summary.histogram.value_at_quantile(0.99),
+    );
+}
+
+/// Splits the stats into slices of time for interval reports and the histogram log
+struct IntervalCollector {
+    /// Empty summary with any registered custom histograms, used to start new slices
+    empty: Summary,
+    /// Results so far in the current slice for each stage
+    slices: BTreeMap<Option<usize>, Summary>,
+    /// Stage of the most recent stat, script events are recorded against this
+    stage: Option<usize>,
+    start: SystemTime,
+    timer: Instant,
+    /// Finished slices, only kept if they're going to be written to the histogram log
+    intervals: Option<Vec<IntervalSummary>>,
+}
+
+impl IntervalCollector {
+    fn new(summary: &Summary, keep: bool, start: Instant) -> Self {
+        Self {
+            empty: summary.cleared(),
+            slices: BTreeMap::new(),
+            stage: None,
+            start: SystemTime::now() + start.saturating_duration_since(Instant::now()),
+            timer: start,
+            intervals: if keep { Some(vec![]) } else { None },
+        }
+    }
+
+    fn slice(&mut self, stage: Option<usize>) -> &mut Summary {
+        let empty = &self.empty;
+        self.slices.entry(stage).or_insert_with(|| empty.cleared())
+    }
+
+    fn record(&mut self, stat: RequestStats) {
+        self.stage = stat.stage;
+        *self.slice(stat.stage) += stat;
+    }
+
+    fn handle_script_event(&mut self, event: ScriptEvents) {
+        if let ScriptEvents::RegisterHistogram { .. } = event {
+            for slice in self.slices.values_mut() {
+                handle_script_event(slice, event.clone());
+            }
+            handle_script_event(&mut self.empty, event);
+        } else {
+            let stage = self.stage;
+            handle_script_event(self.slice(stage), event);
+        }
+    }
+
+    /// All the stages' results for the current slice
+    fn combined(&self) -> Summary {
+        self.slices
+            .values()
+            .fold(self.empty.cleared(), |acc, s| acc + s.clone())
+    }
+
+    /// Finishes the current slice and starts a new one
+    fn finish_slice(&mut self, now: Instant) {
+        let slices = std::mem::take(&mut self.slices);
+        if let Some(intervals) = self.intervals.as_mut() {
+            for (stage, summary) in slices {
+                intervals.push(IntervalSummary {
+                    start: self.start,
+                    duration: now.duration_since(self.timer),
+                    stage,
+                    summary,
+                });
+            }
+        }
+        self.start += now.duration_since(self.timer);
+        self.timer = now;
+    }
+}
+
+/// Collects the stats into a summary. When running stages the stats are tagged with the stage and
+/// a summary is returned for each stage seen. If `hdr_period` is set the results are also split
+/// into slices of that length, or of the report interval if there is one, for the histogram log.
+/// The slices start from `measure_from`, the end of any warm-up
 pub async fn stats_collection(
     mut rx: mpsc::UnboundedReceiver<RequestStats>,
-    script_channel: Option<flume::Sender<RequestStats>>,
-    opt: Arc<Opt>,
-) -> Summary {
-    let mut summary = Summary::new(*opt.timeout);
-    while let Some(stat) = rx.recv().await {
-        if let Some(script) = script_channel.as_ref() {
-            let _ = script.send_async(stat.clone()).await;
+    script_channel: Option<flume::Sender<ScriptMessage>>,
+    script_events: Option<flume::Receiver<ScriptEvents>>,
+    summary: Summary,
+    measure_from: Instant,
+    report_interval: Option<StdDuration>,
+    hdr_period: Option<StdDuration>,
+) -> (Vec<Summary>, Vec<IntervalSummary>) {
+    let mut slices = IntervalCollector::new(&summary, hdr_period.is_some(), measure_from);
+    // One summary for each stage, script events go to the stage of the most recent stat
+    let mut summaries = vec![summary];
+    let mut stage = 0;
+    let start = measure_from;
+    let mut ticker = report_interval
+        .or(hdr_period)
+        .map(|d| interval_at(start + d, d));
+    loop {
+        tokio::select! {
+            stat = rx.recv() => match stat {
+                Some(stat) => {
+                    stage = stat.stage.unwrap_or_default();
+                    if stage >= summaries.len() {
+                        let empty = summaries[summaries.len() - 1].cleared();
+                        summaries.resize(stage + 1, empty);
+                    }
+                    // Scenarios that fail before sending a request only have a scenario result,
+                    // there's no response for the script
+                    let script = script_channel.as_ref().filter(|_| stat.request.is_some());
+                    if let Some(script) = script {
+                        let message = ScriptMessage::Response(Box::new(stat.clone()));
+                        let _ = script.send_async(message).await;
+                    }
+                    if ticker.is_some() {
+                        slices.record(stat.clone());
+                    }
+                    summaries[stage] += stat;
+                }
+                None => break,
+            },
+            Ok(event) = next_script_event(script_events.as_ref()) => {
+                if ticker.is_some() {
+                    slices.handle_script_event(event.clone());
+                }
+                handle_script_event(&mut summaries[stage], event);
+            }
+            now = next_report(ticker.as_mut()) => {
+                if report_interval.is_some() {
+                    print_interval(start, slices.timer, &slices.combined());
+                }
+                slices.finish_slice(now);
+            }
+        }
+    }
+    if ticker.is_some() {
+        slices.finish_slice(Instant::now());
+    }
+    // Wait for the script to handle every response so its updates end up in this summary
+    let flushed = match script_channel.as_ref() {
+        Some(script) => script.send_async(ScriptMessage::Flush).await.is_ok(),
+        None => false,
+    };
+    if let Some(events) = script_events.as_ref() {
+        if flushed {
+            loop {
+                match events.recv_async().await {
+                    Ok(ScriptEvents::Flushed) | Err(_) => break,
+                    Ok(event) => handle_script_event(&mut summaries[stage], event),
+                }
+            }
+        }
+        while let Ok(event) = events.try_recv() {
+            handle_script_event(&mut summaries[stage], event);
         }
-        summary += stat;
     }
-    summary
+    (summaries, slices.intervals.unwrap_or_default())
 }
 
-fn get_request_store(opt: Arc<Opt>) -> RequestStore {
+/// Loads the requests to send along with any stages given in the config file
+fn get_request_store(opt: Arc<Opt>) -> (RequestStore, Vec<Stage>) {
     if let Some(conf) = &opt.config {
-        let config = fs::read_to_string(&conf).unwrap();
+        let config = fs::read_to_string(conf).unwrap();
         let spec = match serde_yaml::from_str::<Specification>(&config) {
             Ok(s) => s,
             Err(e) => match serde_json::from_str::<Specification>(&config) {
@@ -169,56 +867,458 @@ fn get_request_store(opt: Arc<Opt>) -> RequestStore {
                 }
             },
         };
-        RequestStore::create_from_spec(opt.endpoint.clone(), &spec)
+        let stages = spec
+            .stages
+            .iter()
+            .map(|s| {
+                s.parse::<Stage>()
+                    .unwrap_or_else(|e| panic!("Invalid stage {}: {}", s, e))
+            })
+            .collect();
+        (
+            RequestStore::create_from_spec(opt.endpoint.clone(), &spec),
+            stages,
+        )
     } else {
         let req = RequestBuilder::try_from(opt.endpoint.clone()).unwrap();
-        RequestStore {
-            requests: vec![req],
-            weights: vec![1.0],
+        (RequestStore::new(vec![req], vec![1.0]), vec![])
+    }
+}
+
+fn write_results(path: &Path, results: &[LevelSummary]) -> io::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(file, results)?;
+    Ok(())
+}
+
+fn write_hdr_log(path: &Path, results: &[LevelSummary]) -> io::Result<()> {
+    let log_start = match results.first() {
+        Some(res) => res.start,
+        None => return Ok(()),
+    };
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut serializer = V2DeflateSerializer::new();
+    let mut writer = IntervalLogWriterBuilder::new()
+        .add_comment(
+            "Generated by murk, values are in milliseconds, request phases in microseconds",
+        )
+        .with_start_time(log_start)
+        .with_base_time(log_start)
+        .begin_log_with(&mut file, &mut serializer)?;
+    for (i, res) in results.iter().enumerate() {
+        // Stages can have the same number of connections so are tagged by their position
+        let level = match res.stage {
+            Some(_) => format!("s{}", i + 1),
+            None => format!("c{}", res.connections),
+        };
+        for interval in &res.intervals {
+            let start = interval.start.duration_since(log_start).unwrap_or_default();
+            let summary = &interval.summary;
+            let mut histograms = vec![
+                (format!("latency-{}", level), &summary.histogram),
+                (format!("corrected-{}", level), &summary.corrected_histogram),
+            ];
+            for (phase, hist) in &summary.phase_histograms {
+                let phase = phase.to_string().replace(' ', "_");
+                histograms.push((format!("{}-{}", phase, level), hist));
+            }
+            for (name, hist) in &summary.custom_histograms {
+                // Tags can't contain whitespace or commas
+                let name = name.replace(|c: char| c.is_whitespace() || c == ',', "_");
+                histograms.push((format!("{}-{}", name, level), hist));
+            }
+            for (tag, hist) in histograms.iter().filter(|(_, h)| !h.is_empty()) {
+                writer
+                    .write_histogram(*hist, start, interval.duration, Tag::new(tag))
+                    .map_err(io::Error::other)?;
+            }
         }
     }
+    Ok(())
+}
+
+/// Spawns the users for a level or a run of stages
+struct UserSpawner {
+    opt: Arc<Opt>,
+    requests: Arc<RequestStore>,
+    connector: TrackingConnector,
+    /// Client and pool semaphore used by every user in shared mode
+    shared: Option<(Client<TrackingConnector>, Arc<Semaphore>)>,
+    hook: Option<RequestHook>,
+    capture_headers: bool,
+    tx: StatsSender,
+    /// Number of connections, or the peak number when running stages
+    connections: usize,
+    next_id: usize,
+    jobs: FuturesUnordered<JoinHandle<Result<(), RunError>>>,
+    /// Users wait for this before sending any requests
+    start: (watch::Sender<bool>, watch::Receiver<bool>),
+}
+
+impl UserSpawner {
+    fn new(
+        opt: Arc<Opt>,
+        requests: Arc<RequestStore>,
+        connector: TrackingConnector,
+        script_engine: &ScriptingContext,
+        tx: StatsSender,
+        connections: usize,
+    ) -> Self {
+        let users = connections * opt.streams();
+        // In shared mode the semaphore caps the number of requests in flight, and so the number
+        // of connections, at the pool size
+        let shared = if opt.connection_mode == ConnectionMode::Shared {
+            let pool_size = opt.pool_size.unwrap_or(users).max(1);
+            Some((
+                opt.client(connector.clone(), users),
+                Arc::new(Semaphore::new(pool_size)),
+            ))
+        } else {
+            None
+        };
+        Self {
+            opt,
+            requests,
+            connector,
+            shared,
+            hook: script_engine.request_hook(),
+            capture_headers: script_engine.has_response_hook(),
+            tx,
+            connections,
+            next_id: 0,
+            jobs: FuturesUnordered::new(),
+            start: watch::channel(false),
+        }
+    }
+
+    /// Lets the users spawned so far start sending requests, any spawned later start straight
+    /// away
+    fn start(&self) {
+        let _ = self.start.0.send(true);
+    }
+
+    /// Spawns a user for each stream on a new connection. If `stoppable` the users run until the
+    /// returned senders are dropped, otherwise they run for the test duration
+    fn spawn_connection(&mut self, stoppable: bool) -> Vec<oneshot::Sender<()>> {
+        let users = self.connections * self.opt.streams();
+        // Each client has its own pool, with HTTP/2 all the client's streams share one
+        // connection
+        let (client, pool) = match self.shared.as_ref() {
+            Some((client, pool)) => (client.clone(), Some(pool.clone())),
+            None => (self.opt.client(self.connector.clone(), users), None),
+        };
+        let mut stops = vec![];
+        for _ in 0..self.opt.streams() {
+            let stop = if stoppable {
+                let (tx, rx) = oneshot::channel();
+                stops.push(tx);
+                Some(rx)
+            } else {
+                None
+            };
+            let user = User {
+                id: self.next_id,
+                client: client.clone(),
+                pool: pool.clone(),
+                hook: self.hook.clone(),
+                capture_headers: self.capture_headers,
+                connections: self.connections,
+                stop,
+                start: self.start.1.clone(),
+            };
+            self.next_id += 1;
+            self.jobs.push(tokio::task::spawn(run_user(
+                self.tx.clone(),
+                self.requests.clone(),
+                self.opt.clone(),
+                user,
+            )));
+        }
+        stops
+    }
+
+    /// Waits for all the users to stop
+    async fn finish(mut self) {
+        while let Some(j) = self.jobs.next().await {
+            // Closing down jobs
+            if j.is_err() {
+                eprintln!("Job failure, channel closed");
+            }
+        }
+    }
+}
+
+/// Runs the test at each fixed level of connections from `--connections` or `--ramp`, giving the
+/// results for each level
+async fn run_levels(
+    opt: &Arc<Opt>,
+    requests: &Arc<RequestStore>,
+    connector: &TrackingConnector,
+    script_engine: &ScriptingContext,
+    mut summary: Summary,
+) -> Vec<LevelSummary> {
+    let levels = opt.connections();
+    let mut results = vec![];
+    for connections in &levels {
+        // Sequential feeders would otherwise run dry after the first level
+        requests.reset_feeders();
+        if opt.http2 {
+            println!(
+                "Testing for {} concurrent connections with {} streams each",
+                connections,
+                opt.streams()
+            );
+        } else {
+            println!("Testing for {} concurrent connections", connections);
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stats = tokio::task::spawn(stats_collection(
+            rx,
+            script_engine.response_sender(),
+            script_engine.event_receiver(),
+            summary.cleared(),
+            Instant::now() + opt.warmup(),
+            opt.report_interval.map(|d| *d),
+            opt.hdr_period(),
+        ));
+
+        let mut spawner = UserSpawner::new(
+            opt.clone(),
+            requests.clone(),
+            connector.clone(),
+            script_engine,
+            StatsSender {
+                tx,
+                stage: None,
+                measure_from: None,
+            },
+            *connections,
+        );
+        for _ in 0..*connections {
+            spawner.spawn_connection(false);
+        }
+        spawner.start();
+        if let Some(warmup) = opt.warmup {
+            println!("Warming up for {}", warmup);
+            sleep(*warmup).await;
+        }
+        let closed_before = connector.closed_by_server();
+        let level_start = SystemTime::now();
+        let level_timer = Instant::now();
+        spawner.finish().await;
+        let (mut summaries, intervals) = stats.await.unwrap();
+        summary = summaries.remove(0);
+        summary.connections_closed_by_server = connector.closed_by_server() - closed_before;
+        println!("Request summary:\n{}", summary);
+        results.push(LevelSummary {
+            connections: *connections,
+            stage: None,
+            start: level_start,
+            duration: level_timer.elapsed(),
+            summary: summary.clone(),
+            intervals,
+        });
+        if results.len() < levels.len() {
+            sleep(*opt.cooldown).await;
+        }
+    }
+    results
+}
+
+/// Runs through the stages of a load profile adding and removing users as it goes, giving the
+/// results for each stage
+async fn run_stages(
+    opt: &Arc<Opt>,
+    stages: &[Stage],
+    requests: &Arc<RequestStore>,
+    connector: &TrackingConnector,
+    script_engine: &ScriptingContext,
+    summary: &Summary,
+) -> Vec<LevelSummary> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let stats = tokio::task::spawn(stats_collection(
+        rx,
+        script_engine.response_sender(),
+        script_engine.event_receiver(),
+        summary.cleared(),
+        Instant::now(),
+        opt.report_interval.map(|d| *d),
+        opt.hdr_period(),
+    ));
+    let current = Arc::new(AtomicUsize::new(0));
+    let tx = StatsSender {
+        tx,
+        stage: Some(current.clone()),
+        measure_from: None,
+    };
+    let peak = stages.iter().map(|s| s.peak()).max().unwrap_or_default();
+    let mut spawner = UserSpawner::new(
+        opt.clone(),
+        requests.clone(),
+        connector.clone(),
+        script_engine,
+        tx,
+        peak,
+    );
+    spawner.start();
+    // The users on each open connection, the most recently opened are stopped first
+    let mut running: Vec<Vec<oneshot::Sender<()>>> = vec![];
+    let mut starts = vec![];
+    let mut closed = vec![];
+    for (i, stage) in stages.iter().enumerate() {
+        println!("Stage {}: {}", i + 1, stage);
+        current.store(i, Ordering::Relaxed);
+        let stage_start = Instant::now();
+        let stage_end = stage_start + stage.duration;
+        starts.push((SystemTime::now(), stage_start));
+        closed.push(connector.closed_by_server());
+        loop {
+            let users = stage.users_at(stage_start.elapsed());
+            while running.len() < users {
+                running.push(spawner.spawn_connection(true));
+            }
+            running.truncate(users);
+            if Instant::now() >= stage_end {
+                break;
+            }
+            sleep_until(stage_end.min(Instant::now() + STAGE_TICK)).await;
+        }
+    }
+    let end = Instant::now();
+    running.clear();
+    spawner.finish().await;
+    // Once the users have stopped any closes are down to us
+    closed.push(connector.closed_by_server());
+    let (mut summaries, intervals) = stats.await.unwrap();
+    // Stages no stats were recorded in
+    while summaries.len() < stages.len() {
+        summaries.push(summaries[summaries.len() - 1].cleared());
+    }
+    let mut results = vec![];
+    for (i, (stage, mut summary)) in stages.iter().zip(summaries).enumerate() {
+        let (start, timer) = starts[i];
+        let stage_end = starts.get(i + 1).map(|(_, t)| *t).unwrap_or(end);
+        summary.connections_closed_by_server = closed[i + 1] - closed[i];
+        println!("Stage {}: {}\nRequest summary:\n{}", i + 1, stage, summary);
+        results.push(LevelSummary {
+            connections: stage.peak(),
+            stage: Some(stage.to_string()),
+            start,
+            duration: stage_end.duration_since(timer),
+            summary,
+            intervals: intervals
+                .iter()
+                .filter(|s| s.stage == Some(i))
+                .cloned()
+                .collect(),
+        });
+    }
+    results
 }
 
 pub async fn run_loadtest(opt: Arc<Opt>) {
     let req_opt = opt.clone();
 
-    let script_engine = if let Some(script) = opt.script.clone() {
+    let mut script_engine = if let Some(script) = opt.script.clone() {
         ScriptingContext::load(script)
     } else {
         ScriptingContext::empty()
     };
-    let requests = tokio::task::spawn_blocking(move || Arc::new(get_request_store(req_opt)))
+    let (requests, spec_stages) = tokio::task::spawn_blocking(move || get_request_store(req_opt))
         .await
         .unwrap();
-    println!("Collected {} requests. Running load test", requests.len());
-    for connections in &opt.connections() {
-        println!("Testing for {} concurrent connections", connections);
-        let (tx, rx) = mpsc::unbounded_channel();
-        let stats = tokio::task::spawn(stats_collection(
-            rx,
-            script_engine.response_sender(),
-            opt.clone(),
-        ));
-        let mut jobs = FuturesUnordered::new();
-
-        for _ in 0..*connections {
-            jobs.push(tokio::task::spawn(run_user(
-                tx.clone(),
-                requests.clone(),
-                opt.clone(),
-                *connections,
-            )));
+    let stages = Stage::resolve(opt.stages.as_deref().unwrap_or(&spec_stages));
+    if stages.is_empty() && opt.duration.is_none() {
+        eprintln!("A duration is needed unless running stages");
+        return;
+    }
+    if !stages.is_empty() && (opt.connections.is_some() || opt.ramp.is_some()) {
+        eprintln!("--connections and --ramp can't be used with stages");
+        return;
+    }
+    if !stages.is_empty() && opt.warmup.is_some() {
+        eprintln!("--warmup can't be used with stages, use a first stage to warm up instead");
+        return;
+    }
+    script_engine.load_hook().await;
+    let script_requests = script_engine.take_requests();
+    if !requests.scenarios.is_empty() {
+        if opt.selection != SelectionMode::Weighted {
+            eprintln!("Scenarios are picked by weight, --selection can't be used with them");
+            return;
         }
-        while let Some(j) = jobs.next().await {
-            // Closing down jobs
-            if j.is_err() {
-                eprintln!("Job failure, channel closed");
+        if script_engine.request_hook().is_some() || !script_requests.is_empty() {
+            eprintln!(
+                "Scripts can't make requests for scenarios, remove make_request and init_requests"
+            );
+            return;
+        }
+    }
+    let requests = if script_requests.is_empty() {
+        requests
+    } else {
+        match requests.with_overrides(&script_requests) {
+            Ok(requests) => requests,
+            Err(e) => {
+                eprintln!("Invalid requests from script: {}", e);
+                return;
             }
         }
-        std::mem::drop(tx);
-        let summary = stats.await.unwrap();
-        println!("Request summary:\n{}", summary);
-        sleep(StdDuration::from_secs(2)).await;
+    };
+    let requests = Arc::new(requests);
+    if requests.scenarios.is_empty() {
+        println!("Collected {} requests. Running load test", requests.len());
+    } else {
+        println!(
+            "Collected {} scenarios. Running load test",
+            requests.scenarios.len()
+        );
+    }
+    let tls = TlsOptions {
+        ca_cert: opt.cacert.clone(),
+        cert: opt.cert.clone(),
+        key: opt.key.clone(),
+        insecure: opt.insecure,
+        sni: opt.sni.clone(),
+        http2: opt.http2,
+    };
+    // Shared between users so TLS sessions can be resumed across connections
+    let connector = match TrackingConnector::with_tls(&tls) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Invalid TLS configuration: {}", e);
+            return;
+        }
+    };
+    let mut summary = Summary::new(*opt.timeout);
+    summary.track_status_latency = opt.status_latency;
+    let results = if stages.is_empty() {
+        run_levels(&opt, &requests, &connector, &script_engine, summary).await
+    } else {
+        run_stages(
+            &opt,
+            &stages,
+            &requests,
+            &connector,
+            &script_engine,
+            &summary,
+        )
+        .await
+    };
+
+    if let Some(output) = opt.output.as_ref() {
+        if let Err(e) = write_results(output, &results) {
+            eprintln!("Failed to write results to {}: {}", output.display(), e);
+        }
+    }
+    if let Some(hdr_log) = opt.hdr_log.as_ref() {
+        if let Err(e) = write_hdr_log(hdr_log, &results) {
+            eprintln!(
+                "Failed to write histogram log to {}: {}",
+                hdr_log.display(),
+                e
+            );
+        }
     }
 
     if script_engine.is_active() {
@@ -228,3 +1328,90 @@ pub async fn run_loadtest(opt: Arc<Opt>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use std::convert::Infallible;
+
+    fn runner(run_for: StdDuration, interval: Option<StdDuration>) -> RequestRunner {
+        RequestRunner {
+            client: UserClient {
+                client: Client::builder().build::<_, Body>(TrackingConnector::new()),
+                pool: None,
+                clock: Clock::new(),
+                timeout: StdDuration::from_secs(5),
+                connections: 1,
+                capture_headers: false,
+            },
+            interval,
+            intended_start: Instant::now(),
+            end: Box::pin(sleep(run_for)),
+        }
+    }
+
+    #[tokio::test]
+    async fn scenario_steps_read_response_headers() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let res = match req.uri().path() {
+                    "/login" => Response::builder()
+                        .header("x-token", "abc")
+                        .body(Body::empty()),
+                    "/jobs/abc" => Response::builder().body(Body::from(r#"{"state": "done"}"#)),
+                    _ => Response::builder().status(404).body(Body::empty()),
+                };
+                Ok::<_, Infallible>(res.unwrap())
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let spec: Specification = serde_yaml::from_str(
+            r#"
+            scenarios:
+              job:
+                steps:
+                  - path: /login
+                    extract:
+                      token:
+                        header: x-token
+                  - path: /jobs/{{token}}
+                    pollIntervalMs: 1
+                    until:
+                      json:
+                        /state: done
+            "#,
+        )
+        .unwrap();
+        let store = RequestStore::create_from_spec(url, &spec);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let tx = StatsSender {
+            tx,
+            stage: None,
+            measure_from: None,
+        };
+        let mut runner = runner(StdDuration::from_millis(200), None);
+        run_scenarios(&tx, &store, &mut runner).await.unwrap();
+        std::mem::drop(tx);
+
+        let mut summary = Summary::new(StdDuration::from_secs(5));
+        while let Some(stat) = rx.recv().await {
+            summary += stat;
+        }
+        let counts = &summary.scenarios["job"];
+        assert!(counts.completed > 0);
+        assert_eq!(counts.failed, 0);
+        assert!(summary.assertion_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pauses_move_the_intended_start() {
+        let mut runner = runner(StdDuration::from_secs(5), Some(StdDuration::from_millis(1)));
+        assert!(runner.pause(StdDuration::from_millis(20)).await);
+        assert!(runner.intended_start.elapsed() < StdDuration::from_millis(5));
+    }
+}
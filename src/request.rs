@@ -1,21 +1,121 @@
+use crate::expect::Expectations;
+use crate::feeder::{feeders_for, Feeder};
+use crate::scenario::Scenario;
+use crate::schema::ExampleGenerator;
 use crate::spec::*;
-use bytes::Bytes;
+use crate::template::{Context, Template, UrlTemplate, Variables};
+use bytes::{Bytes, BytesMut};
+use futures::executor::block_on;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
 use hyper::{
-    header::{HeaderName, HeaderValue},
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, COOKIE},
     Body, HeaderMap, Method, Request,
 };
-use random_choice::random_choice;
+use mpart_async::client::{ByteStream, MultipartRequest};
+use openapiv3::{Parameter, ParameterData, ReferenceOr};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::borrow::Cow;
+use std::convert::Infallible;
 pub use std::convert::TryFrom;
 use std::fs;
-use std::path::Path;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::time::sleep_until;
 use url::Url;
 
+/// Size of the chunks read from files when streaming them into a request body
+const CHUNK_SIZE: usize = 64 * 1024;
+
+type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
 pub struct RequestStore {
     /// List of weights. This list will be either be empty or the same length as the requests vector
     pub(crate) weights: Vec<f64>,
-    /// List of the requests to use. I'm kind of assuming since I'm using a Bytes to store the body
-    /// that these will be relatively cheap to clone... But there's only one way to find out
+    /// List of the requests to use. Bodies from files are only read when the request is sent so
+    /// these should be relatively cheap to clone
     pub(crate) requests: Vec<RequestBuilder>,
+    /// Description of each request, made once up front so users can share them
+    infos: Vec<Arc<RequestInfo>>,
+    /// Scenarios for users to run instead of individual requests
+    pub(crate) scenarios: Vec<Scenario>,
+    /// Feeders used by the requests and scenarios
+    feeders: Vec<Arc<Feeder>>,
+    /// Position in the requests shared between users in sequential mode
+    cursor: AtomicUsize,
+}
+
+/// How each user picks the next request to send
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SelectionMode {
+    /// Make a new weighted random pick for every request
+    Weighted,
+    /// Go through the requests in order, users share a position so every request gets sent
+    Sequential,
+    /// Go through a new random permutation of the requests on every pass
+    Shuffle,
+}
+
+impl FromStr for SelectionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weighted" => Ok(Self::Weighted),
+            "sequential" => Ok(Self::Sequential),
+            "shuffle" => Ok(Self::Shuffle),
+            _ => Err(format!("Unknown selection mode: {}", s)),
+        }
+    }
+}
+
+/// Endless iterator of indexes into a `RequestStore`
+pub struct RequestSelector<'a> {
+    store: &'a RequestStore,
+    mode: SelectionMode,
+    /// Weights for weighted mode, if the weights are all zero requests are picked uniformly
+    weights: Option<WeightedIndex<f64>>,
+    order: Vec<usize>,
+    position: usize,
+}
+
+impl<'a> Iterator for RequestSelector<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        let len = self.store.len();
+        if len == 0 {
+            return None;
+        }
+        let index = match self.mode {
+            SelectionMode::Weighted => {
+                let mut rng = rand::thread_rng();
+                match self.weights.as_ref() {
+                    Some(weights) => weights.sample(&mut rng),
+                    None => rng.gen_range(0..len),
+                }
+            }
+            SelectionMode::Sequential => self.store.cursor.fetch_add(1, Ordering::Relaxed) % len,
+            SelectionMode::Shuffle => {
+                if self.position >= self.order.len() {
+                    self.order = (0..len).collect();
+                    self.order.shuffle(&mut rand::thread_rng());
+                    self.position = 0;
+                }
+                self.position += 1;
+                self.order[self.position - 1]
+            }
+        };
+        Some(index)
+    }
 }
 
 #[derive(Clone)]
@@ -23,7 +123,346 @@ pub struct RequestBuilder {
     url: Url,
     method: Method,
     headers: HeaderMap,
-    body: Bytes,
+    body: BodySource,
+    pacing: Option<PacedUpload>,
+    /// Checks to make on the response
+    expect: Option<Arc<Expectations>>,
+    /// Name of the requestData entry in the specification this request was made from
+    data_name: Option<String>,
+    /// Parts of the request to fill in from templates each time it's sent
+    template: Option<Arc<RequestTemplate>>,
+}
+
+/// The parts of a request which contain template expressions
+#[derive(Clone, Default)]
+pub struct RequestTemplate {
+    /// Replaces the URL when set
+    pub url: Option<UrlTemplate>,
+    /// Headers to add, replacing any existing header with the same name
+    pub headers: Vec<(HeaderName, Template)>,
+    /// Replaces a constant body when set
+    pub body: Option<Template>,
+    /// Feeders providing the variables used, a row is taken from each for every request
+    pub feeders: Vec<Arc<Feeder>>,
+    /// Name of the first file the request's body is read from, for `{{file_name}}`
+    pub file_name: Option<String>,
+}
+
+impl RequestTemplate {
+    pub fn is_empty(&self) -> bool {
+        self.url.is_none() && self.headers.is_empty() && self.body.is_none()
+    }
+
+    /// Names of the variables used
+    pub fn variables(&self) -> Vec<&str> {
+        let url = self.url.iter().flat_map(|u| u.variables());
+        let headers = self.headers.iter().flat_map(|(_, v)| v.variables());
+        let body = self.body.iter().flat_map(|b| b.variables());
+        url.chain(headers).chain(body).collect()
+    }
+}
+
+/// Description of a request passed along with its stats
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestInfo {
+    /// Name used to identify the request in reports
+    pub name: Arc<str>,
+    pub method: String,
+    pub url: String,
+    /// Name of the requestData entry in the specification this request was made from
+    pub data_name: Option<String>,
+    /// Files the body is read from
+    pub files: Vec<PathBuf>,
+    pub body_len: usize,
+    /// Scenario the request is a step of
+    pub scenario: Option<Arc<str>>,
+}
+
+/// Sends the body in chunks of `bytes_per_second * interval` every interval
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PacedUpload {
+    pub bytes_per_second: usize,
+    pub interval: Duration,
+}
+
+impl PacedUpload {
+    fn chunk_size(&self) -> usize {
+        ((self.bytes_per_second as f64 * self.interval.as_secs_f64()) as usize).max(1)
+    }
+}
+
+/// Records when the last chunk of a paced body was sent
+#[derive(Clone, Debug, Default)]
+pub struct UploadTimer(Arc<Mutex<Option<Instant>>>);
+
+impl UploadTimer {
+    fn finish(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn finished(&self) -> Option<Instant> {
+        *self.0.lock().unwrap()
+    }
+}
+
+struct PacedState {
+    inner: BodyStream,
+    buf: BytesMut,
+    done: bool,
+    next_send: Option<Instant>,
+}
+
+fn paced_stream(inner: BodyStream, pacing: PacedUpload, timer: UploadTimer) -> BodyStream {
+    let chunk_size = pacing.chunk_size();
+    let state = PacedState {
+        inner,
+        buf: BytesMut::new(),
+        done: false,
+        next_send: None,
+    };
+    let stream = stream::try_unfold(state, move |mut state| {
+        let timer = timer.clone();
+        async move {
+            while !state.done && state.buf.len() < chunk_size {
+                match state.inner.next().await {
+                    Some(chunk) => state.buf.extend_from_slice(&chunk?),
+                    None => state.done = true,
+                }
+            }
+            if state.buf.is_empty() {
+                return Ok(None);
+            }
+            if let Some(next_send) = state.next_send {
+                sleep_until(next_send.into()).await;
+            }
+            let len = chunk_size.min(state.buf.len());
+            let chunk = state.buf.split_to(len).freeze();
+            if state.done && state.buf.is_empty() {
+                timer.finish();
+            }
+            let now = state.next_send.unwrap_or_else(Instant::now);
+            state.next_send = Some(now + pacing.interval);
+            Ok(Some((chunk, state)))
+        }
+    });
+    Box::pin(stream)
+}
+
+/// Works out the byte rate of a WAV file from its format chunk
+fn wav_byte_rate(header: &[u8]) -> Option<usize> {
+    if header.len() < 12 || &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+        return None;
+    }
+    let mut chunks = &header[12..];
+    while chunks.len() >= 8 {
+        let len = u32::from_le_bytes([chunks[4], chunks[5], chunks[6], chunks[7]]) as usize;
+        if &chunks[0..4] == b"fmt " {
+            let fmt = chunks.get(8..24)?;
+            let channels = u16::from_le_bytes([fmt[2], fmt[3]]) as usize;
+            let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]) as usize;
+            let bits_per_sample = u16::from_le_bytes([fmt[14], fmt[15]]) as usize;
+            return Some(sample_rate * channels * bits_per_sample / 8);
+        }
+        // Chunks are padded to an even length
+        chunks = chunks.get(8 + len + (len & 1)..)?;
+    }
+    None
+}
+
+fn wav_header(body: &BodySource) -> Option<Vec<u8>> {
+    let path = match body {
+        BodySource::Bytes(b) => return Some(b.to_vec()),
+        BodySource::File { path, .. } => path,
+        BodySource::Multipart(m) => &m.files.first()?.path,
+    };
+    let mut header = vec![0; 1024];
+    let len = fs::File::open(path).ok()?.read(&mut header).ok()?;
+    header.truncate(len);
+    Some(header)
+}
+
+fn paced_upload(pacing: &Pacing, body: &BodySource) -> Option<PacedUpload> {
+    let bytes_per_second = match pacing.rate {
+        PacingRate::BytesPerSecond(rate) => rate,
+        PacingRate::RealTime => match wav_header(body).and_then(|h| wav_byte_rate(&h)) {
+            Some(rate) => rate,
+            None => {
+                println!("Couldn't read a WAV header, body won't be paced");
+                return None;
+            }
+        },
+    };
+    Some(PacedUpload {
+        bytes_per_second,
+        interval: Duration::from_millis(pacing.chunk_ms),
+    })
+}
+
+/// Where the body for a request comes from. Anything coming from a file is streamed from disk
+/// when the request is made so memory use doesn't depend on the size of the corpus
+#[derive(Clone, Debug)]
+pub enum BodySource {
+    Bytes(Bytes),
+    File { path: PathBuf, len: usize },
+    Multipart(MultipartSource),
+}
+
+#[derive(Clone, Debug)]
+pub struct MultipartSource {
+    boundary: String,
+    fields: Vec<FormField>,
+    files: Vec<MultipartFile>,
+    len: usize,
+}
+
+#[derive(Clone, Debug)]
+struct MultipartFile {
+    name: String,
+    filename: String,
+    content_type: String,
+    path: PathBuf,
+}
+
+fn file_stream(path: PathBuf) -> BodyStream {
+    let stream = stream::once(File::open(path))
+        .map_ok(|file| {
+            stream::try_unfold(file, |mut file| async move {
+                let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+                if file.read_buf(&mut buf).await? == 0 {
+                    Ok(None)
+                } else {
+                    Ok(Some((buf.freeze(), file)))
+                }
+            })
+        })
+        .try_flatten();
+    Box::pin(stream)
+}
+
+impl MultipartSource {
+    fn build<E, S>(&self, mut stream: impl FnMut(&Path) -> S) -> MultipartRequest<S>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+    {
+        let mut req = MultipartRequest::new(self.boundary.as_str());
+        for field in &self.fields {
+            req.add_field(field.name.as_str(), field.value.as_str());
+        }
+        for file in &self.files {
+            req.add_stream(
+                file.name.as_str(),
+                file.filename.as_str(),
+                file.content_type.as_str(),
+                stream(&file.path),
+            );
+        }
+        req
+    }
+
+    fn stream(&self) -> MultipartRequest<BodyStream> {
+        self.build(|path| file_stream(path.to_path_buf()))
+    }
+
+    pub fn content_type(&self) -> HeaderValue {
+        let content_type = format!("multipart/form-data; boundary={}", self.boundary);
+        HeaderValue::from_str(&content_type).unwrap()
+    }
+}
+
+impl BodySource {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Bytes(b) => b.len(),
+            Self::File { len, .. } => *len,
+            Self::Multipart(m) => m.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn stream(self) -> BodyStream {
+        match self {
+            Self::Bytes(b) => Box::pin(stream::once(async move { Ok(b) })),
+            Self::File { path, .. } => file_stream(path),
+            Self::Multipart(m) => Box::pin(m.stream()),
+        }
+    }
+}
+
+impl From<BodySource> for Body {
+    fn from(source: BodySource) -> Self {
+        match source {
+            BodySource::Bytes(b) => b.into(),
+            source => Body::wrap_stream(source.stream()),
+        }
+    }
+}
+
+/// Changes to make to a request before sending it, returned by a script's `make_request` hook
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestOverride {
+    /// Index of the request in the store to start from instead of the selected one
+    pub index: Option<usize>,
+    pub method: Option<String>,
+    /// Either a full URL or one relative to the request's URL
+    pub url: Option<String>,
+    /// Headers to add, replacing any existing header with the same name
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Bytes>,
+}
+
+impl RequestOverride {
+    /// Applies the changes to a copy of the request. The request's expectations were written for
+    /// the original method, URL and body so they're dropped when any of those change, as is upload
+    /// pacing when the body is replaced
+    pub fn apply(&self, req: &RequestBuilder) -> Result<RequestBuilder, String> {
+        let mut req = req.clone();
+        if self.method.is_some() || self.url.is_some() || self.body.is_some() {
+            req.expect = None;
+        }
+        if self.body.is_some() {
+            req.pacing = None;
+        }
+        if let Some(template) = req.template.take() {
+            let mut template = (*template).clone();
+            if self.url.is_some() {
+                template.url = None;
+            }
+            if self.body.is_some() {
+                template.body = None;
+            }
+            template.headers.retain(|(name, _)| {
+                !self
+                    .headers
+                    .iter()
+                    .any(|(n, _)| name.as_str().eq_ignore_ascii_case(n))
+            });
+            req = req.with_template(template);
+        }
+        if let Some(method) = self.method.as_ref() {
+            req.method = Method::from_bytes(method.to_uppercase().as_bytes())
+                .map_err(|e| format!("Invalid method {}: {}", method, e))?;
+        }
+        if let Some(url) = self.url.as_ref() {
+            req.url = req
+                .url
+                .join(url)
+                .map_err(|e| format!("Invalid URL {}: {}", url, e))?;
+        }
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|e| format!("Invalid header value {}: {}", value, e))?;
+            req.headers.insert(name, value);
+        }
+        if let Some(body) = self.body.as_ref() {
+            req.body = BodySource::Bytes(body.clone());
+        }
+        Ok(req)
+    }
 }
 
 impl TryFrom<String> for RequestBuilder {
@@ -41,13 +480,93 @@ impl From<Url> for RequestBuilder {
             url,
             method: Method::GET,
             headers: Default::default(),
-            body: Bytes::new(),
+            body: BodySource::Bytes(Bytes::new()),
+            pacing: None,
+            expect: None,
+            data_name: None,
+            template: None,
         }
     }
 }
 
 impl RequestBuilder {
+    pub fn new(url: Url, method: Method, headers: HeaderMap, body: BodySource) -> Self {
+        Self {
+            url,
+            method,
+            headers,
+            body,
+            pacing: None,
+            expect: None,
+            data_name: None,
+            template: None,
+        }
+    }
+
+    pub fn with_expectations(mut self, expect: Option<Arc<Expectations>>) -> Self {
+        self.expect = expect;
+        self
+    }
+
+    pub fn with_template(mut self, mut template: RequestTemplate) -> Self {
+        template.file_name = self
+            .files()
+            .first()
+            .and_then(|f| f.file_name())
+            .map(|f| f.to_string_lossy().into_owned());
+        self.template = Some(Arc::new(template)).filter(|t| !t.is_empty());
+        self
+    }
+
+    /// Names of the variables used in the templates
+    pub fn variables(&self) -> Vec<&str> {
+        self.template
+            .as_ref()
+            .map(|t| t.variables())
+            .unwrap_or_default()
+    }
+
+    /// Sets the variables from the next row of each feeder the request uses, returns false if a
+    /// feeder has run out of rows
+    pub fn feed(&self, vars: &mut Variables) -> bool {
+        self.template
+            .iter()
+            .flat_map(|t| &t.feeders)
+            .all(|f| f.feed(vars))
+    }
+
+    /// Fills in any templates, giving the request to send
+    pub fn render(&self, vars: &Variables) -> Result<Cow<'_, Self>, String> {
+        let template = match self.template.as_ref() {
+            Some(template) => template,
+            None => return Ok(Cow::Borrowed(self)),
+        };
+        let ctx = Context::new(vars, template.file_name.as_deref());
+        let mut req = self.clone();
+        req.template = None;
+        if let Some(url) = template.url.as_ref() {
+            req.url = url.render(&ctx)?;
+        }
+        for (name, value) in &template.headers {
+            let value = value.render(&ctx)?;
+            let value = HeaderValue::from_str(&value)
+                .map_err(|e| format!("Invalid header value {}: {}", value, e))?;
+            req.headers.insert(name.clone(), value);
+        }
+        if let Some(body) = template.body.as_ref() {
+            req.body = BodySource::Bytes(Bytes::from(body.render(&ctx)?));
+        }
+        Ok(Cow::Owned(req))
+    }
+
     pub fn request(&self) -> Request<Body> {
+        self.timed_request().0
+    }
+
+    /// Creates the request along with a timer which records when the upload of a paced body
+    /// finished
+    pub fn timed_request(&self) -> (Request<Body>, UploadTimer) {
+        let timer = UploadTimer::default();
         let mut builder = Request::builder()
             .uri(self.url.as_str())
             .method(self.method.clone());
@@ -55,92 +574,484 @@ impl RequestBuilder {
             for (k, v) in &self.headers {
                 headers.insert(k, v.clone());
             }
+            // Streamed bodies would otherwise be sent chunked. Paced bodies are left chunked as
+            // they're meant to look like a live stream with no known length
+            if self.pacing.is_none() && !matches!(self.body, BodySource::Bytes(_)) {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+            }
         }
-        builder.body(self.body.clone().into()).unwrap()
+        let body = match self.pacing {
+            Some(pacing) => {
+                let stream = paced_stream(self.body.clone().stream(), pacing, timer.clone());
+                Body::wrap_stream(stream)
+            }
+            None => self.body.clone().into(),
+        };
+        (builder.body(body).unwrap(), timer)
     }
 
     pub fn body_len(&self) -> usize {
         self.body.len()
     }
+
+    pub fn expectations(&self) -> Option<&Expectations> {
+        self.expect.as_deref()
+    }
+
+    /// Name to identify the request in reports, the method and path along with any files sent
+    pub fn name(&self) -> String {
+        let path = match self.template.as_ref().and_then(|t| t.url.as_ref()) {
+            Some(url) => url.name(),
+            None => self.url[url::Position::BeforePath..].to_string(),
+        };
+        let mut name = format!("{} {}", self.method, path);
+        let files = match &self.body {
+            BodySource::Bytes(_) => vec![],
+            BodySource::File { path, .. } => vec![path.display().to_string()],
+            BodySource::Multipart(m) => m.files.iter().map(|f| f.filename.clone()).collect(),
+        };
+        if !files.is_empty() {
+            name.push_str(&format!(" ({})", files.join(", ")));
+        }
+        name
+    }
+
+    /// Files the body is read from
+    pub fn files(&self) -> Vec<PathBuf> {
+        match &self.body {
+            BodySource::Bytes(_) => vec![],
+            BodySource::File { path, .. } => vec![path.clone()],
+            BodySource::Multipart(m) => m.files.iter().map(|f| f.path.clone()).collect(),
+        }
+    }
+
+    pub fn info(&self, name: Arc<str>) -> RequestInfo {
+        RequestInfo {
+            name,
+            method: self.method.to_string(),
+            url: self.url.to_string(),
+            data_name: self.data_name.clone(),
+            files: self.files(),
+            body_len: self.body.len(),
+            scenario: None,
+        }
+    }
 }
 
-fn bodies_from_path(path: &Path) -> Vec<Bytes> {
+fn files_from_path(path: &Path) -> Vec<PathBuf> {
     if path.is_file() {
-        // just open and read all
-        vec![Bytes::from(fs::read(path).unwrap())]
+        vec![path.to_path_buf()]
     } else if path.is_dir() {
-        let mut res = vec![];
         let dir_stream = fs::read_dir(path).unwrap();
-        for entry in dir_stream {
-            let path = entry.unwrap().path();
-            if path.is_file() {
-                if let Ok(b) = fs::read(&path) {
-                    res.push(Bytes::from(b));
-                } else {
+        dir_stream
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .collect()
+    } else {
+        panic!("Invalid path {} no data found", path.display());
+    }
+}
+
+pub(crate) fn bodies_from_path(path: &Path) -> Vec<BodySource> {
+    let mut res = vec![];
+    for path in files_from_path(path) {
+        if let Ok(meta) = fs::metadata(&path) {
+            res.push(BodySource::File {
+                path,
+                len: meta.len() as usize,
+            });
+        } else {
+            println!("Couldn't read: {}", path.display());
+        }
+    }
+    res
+}
+
+/// Creates the bodies for a multipart request, the files will be streamed in when the request is
+/// sent
+pub(crate) fn multipart_bodies(body: &MultipartBody) -> Vec<MultipartSource> {
+    // A folder gives a choice of file for its field. Every combination would grow too quickly
+    // with a few large folders so the fields go through their files together, starting again
+    // from the first when they run out, making a body for each file in the largest folder
+    let choices = body
+        .files
+        .iter()
+        .map(|file| (file, files_from_path(&file.path)))
+        .collect::<Vec<_>>();
+    let bodies = if choices.iter().any(|(_, paths)| paths.is_empty()) {
+        0
+    } else {
+        choices
+            .iter()
+            .map(|(_, paths)| paths.len())
+            .max()
+            .unwrap_or(1)
+    };
+    let combinations = (0..bodies).map(|i| {
+        choices
+            .iter()
+            .map(|(file, paths)| (*file, paths[i % paths.len()].clone()))
+            .collect::<Vec<_>>()
+    });
+    let boundary = MultipartRequest::<ByteStream>::default()
+        .get_boundary()
+        .to_string();
+    let mut res = vec![];
+    'outer: for files in combinations {
+        let mut file_len = 0;
+        let mut parts = vec![];
+        for (file, path) in files {
+            match fs::metadata(&path) {
+                Ok(meta) => file_len += meta.len() as usize,
+                Err(_) => {
                     println!("Couldn't read: {}", path.display());
+                    continue 'outer;
                 }
             }
+            let filename = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let content_type = file.content_type.clone().unwrap_or_else(|| {
+                mime_guess::from_path(&path)
+                    .first_or_octet_stream()
+                    .to_string()
+            });
+            parts.push(MultipartFile {
+                name: file.name.clone(),
+                filename,
+                content_type,
+                path,
+            });
         }
-        res
-    } else {
-        panic!("Invalid path {} no data found", path.display());
+        let mut source = MultipartSource {
+            boundary: boundary.clone(),
+            fields: body.fields.clone(),
+            files: parts,
+            len: 0,
+        };
+        // Work out the length of everything but the files so we can send a content length
+        let framing = source.build(|_| stream::empty::<Result<Bytes, Infallible>>());
+        let framing =
+            block_on(framing.try_fold(0, |acc, chunk| async move { Ok(acc + chunk.len()) }))
+                .unwrap();
+        source.len = framing + file_len;
+        res.push(source);
+    }
+    res
+}
+
+/// Percent encodes the characters `Url::join` would otherwise treat as delimiters so a value can
+/// be put into a single path segment
+fn encode_path_value(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('/', "%2F")
+        .replace('?', "%3F")
+        .replace('#', "%23")
+        // Generated values aren't templates, even if an example looks like one
+        .replace('{', "%7B")
+        .replace('}', "%7D")
+}
+
+/// The parts of a request that can be generated from the operation's parameters and requestBody
+#[derive(Default)]
+struct GeneratedRequest {
+    path: String,
+    headers: HeaderMap,
+    query: Vec<(String, String)>,
+    body: Option<Bytes>,
+}
+
+/// The parameter's data along with where it goes in the request
+fn parameter_parts(param: &Parameter) -> (&ParameterData, &'static str) {
+    match param {
+        Parameter::Path { parameter_data, .. } => (parameter_data, "path"),
+        Parameter::Query { parameter_data, .. } => (parameter_data, "query"),
+        Parameter::Header { parameter_data, .. } => (parameter_data, "header"),
+        Parameter::Cookie { parameter_data, .. } => (parameter_data, "cookie"),
     }
 }
 
+/// Generates the parts of the request, failing if a value can't be made for a path parameter as
+/// the request would be sent with the placeholder left in the path
+fn generate_request<'a>(
+    path: &str,
+    item_params: &'a [ReferenceOr<Parameter>],
+    op: &'a Operation,
+    gen: &ExampleGenerator<'a>,
+) -> Result<GeneratedRequest, String> {
+    let mut res = GeneratedRequest {
+        path: path.to_string(),
+        ..Default::default()
+    };
+    let op_params = op
+        .parameters
+        .iter()
+        .filter_map(|p| gen.parameter(p))
+        .collect::<Vec<_>>();
+    // The operation's parameters replace the path item's ones with the same name and location
+    let item_params = item_params
+        .iter()
+        .filter_map(|p| gen.parameter(p))
+        .filter(|p| {
+            let (data, location) = parameter_parts(p);
+            !op_params.iter().any(|o| {
+                let (op_data, op_location) = parameter_parts(o);
+                op_data.name == data.name && op_location == location
+            })
+        });
+    let mut cookies = vec![];
+    for param in item_params.chain(op_params.iter().copied()) {
+        let (data, location) = parameter_parts(param);
+        if location != "path" && !data.required {
+            continue;
+        }
+        let value = match gen.parameter_value(data) {
+            Some(v) => v,
+            None if matches!(param, Parameter::Path { .. }) => {
+                return Err(format!(
+                    "Couldn't create a value for path parameter {}",
+                    data.name
+                ));
+            }
+            None => {
+                println!("Couldn't create a value for parameter {}", data.name);
+                continue;
+            }
+        };
+        match param {
+            Parameter::Path { .. } => {
+                let template = format!("{{{}}}", data.name);
+                res.path = res.path.replace(&template, &encode_path_value(&value));
+            }
+            Parameter::Query { .. } => res.query.push((data.name.clone(), value)),
+            Parameter::Header { .. } => {
+                match (
+                    HeaderName::from_bytes(data.name.as_bytes()),
+                    HeaderValue::from_str(&value),
+                ) {
+                    (Ok(name), Ok(value)) => {
+                        res.headers.insert(name, value);
+                    }
+                    _ => println!(
+                        "Invalid value {:?} for header {}, skipping it",
+                        value, data.name
+                    ),
+                }
+            }
+            Parameter::Cookie { .. } => {
+                if HeaderValue::from_str(&value).is_ok() && !value.contains(';') {
+                    cookies.push(format!("{}={}", data.name, value));
+                } else {
+                    println!(
+                        "Invalid value {:?} for cookie {}, skipping it",
+                        value, data.name
+                    );
+                }
+            }
+        }
+    }
+    if !cookies.is_empty() {
+        // Each cookie was checked so they're valid together
+        let value = HeaderValue::from_str(&cookies.join("; ")).unwrap();
+        res.headers.insert(COOKIE, value);
+    }
+    if let Some(body) = op.request_body.as_ref().and_then(|b| gen.request_body(b)) {
+        // Only JSON bodies can be created from a schema
+        let json = body.content.iter().find(|(ty, _)| {
+            let ty = ty.split(';').next().unwrap_or_default().trim();
+            ty == "application/json" || ty.ends_with("+json")
+        });
+        if let Some((content_type, media)) = json {
+            if let Some(value) = gen.media_value(media) {
+                res.headers
+                    .insert(CONTENT_TYPE, HeaderValue::from_str(content_type).unwrap());
+                res.body = Some(Bytes::from(value.to_string()));
+            }
+        }
+    }
+    Ok(res)
+}
+
 fn requests_from_operation(
-    url: Url,
+    base_uri: &Url,
+    path: &str,
+    item_params: &[ReferenceOr<Parameter>],
     method: Method,
     op: &Operation,
+    gen: &ExampleGenerator,
+    feeders: &[Arc<Feeder>],
 ) -> (Vec<f64>, Vec<RequestBuilder>) {
     let mut weights = vec![];
     let mut requests = vec![];
-    if op.is_empty() {
-        requests.push(RequestBuilder {
-            url: url.clone(),
+    let expectations = |expect: Option<&Expect>| {
+        expect.map(|e| {
+            let e = Expectations::new(e)
+                .unwrap_or_else(|e| panic!("Invalid expect block for {} {}: {}", method, path, e));
+            Arc::new(e)
+        })
+    };
+    let op_expect = expectations(op.expect.as_ref());
+    let parse = |s: &str| {
+        Template::parse(s)
+            .unwrap_or_else(|e| panic!("Invalid template for {} {}: {}", method, path, e))
+    };
+    // Outside of scenarios variables can only come from feeders
+    let add_feeders = |template: &mut RequestTemplate| {
+        let vars = template.variables();
+        if let Some(var) = vars.iter().find(|v| !feeders.iter().any(|f| f.provides(v))) {
+            panic!("Unknown variable {} in {} {}", var, method, path);
+        }
+        template.feeders = feeders_for(feeders, vars);
+    };
+    let generated = match generate_request(path, item_params, op, gen) {
+        Ok(generated) => generated,
+        Err(e) => {
+            println!("Skipping {} {}: {}", method, path, e);
+            return (vec![], vec![]);
+        }
+    };
+    let url = base_uri.join(&generated.path).expect("Invalid method name");
+    let path_template = parse(&generated.path);
+    if op.request_data.is_empty() {
+        let mut url = url.clone();
+        for (name, value) in &generated.query {
+            url.query_pairs_mut().append_pair(name, value);
+        }
+        let query = generated
+            .query
+            .iter()
+            .map(|(name, value)| (name.clone(), Template::text(value)))
+            .collect();
+        let url_template = UrlTemplate::new(base_uri, path_template.clone(), vec![], query);
+        let mut template = RequestTemplate {
+            url: Some(url_template).filter(|u| !u.is_constant()),
+            ..Default::default()
+        };
+        add_feeders(&mut template);
+        let req = RequestBuilder {
+            url,
             method: method.clone(),
-            headers: Default::default(),
-            body: Bytes::new(),
-        });
+            headers: generated.headers.clone(),
+            body: BodySource::Bytes(generated.body.clone().unwrap_or_default()),
+            pacing: None,
+            expect: op_expect.clone(),
+            data_name: None,
+            template: None,
+        };
+        requests.push(req.with_template(template));
         weights.push(op.weight as f64);
     }
-    for (_, v) in &op.request_data {
+    for (data_name, v) in &op.request_data {
         let mut url = url.clone();
-        let mut headers = HeaderMap::new();
+        let mut headers = generated.headers.clone();
+        let mut template = RequestTemplate::default();
+        // Given query values replace any generated ones
+        let mut query = generated
+            .query
+            .iter()
+            .filter(|(name, _)| {
+                !v.parameters.iter().any(|p| match p {
+                    TestParameter::Query { name: n, .. } => n == name,
+                    _ => false,
+                })
+            })
+            .map(|(name, value)| (name.clone(), Template::text(value)))
+            .collect::<Vec<_>>();
+        let mut segments = vec![];
         for param in &v.parameters {
             match param {
                 TestParameter::Header { name, value } => {
-                    let name = HeaderName::from_bytes(name.as_bytes()).unwrap();
-                    let value = HeaderValue::from_str(value.as_str()).unwrap();
-                    headers.insert(name, value);
+                    let name = HeaderName::from_bytes(name.as_bytes()).unwrap_or_else(|e| {
+                        panic!("Invalid template for {} {}: {}: {}", method, path, name, e)
+                    });
+                    let value = parse(value);
+                    match value.constant() {
+                        Some(value) => {
+                            let value = HeaderValue::from_str(&value).unwrap_or_else(|e| {
+                                panic!("Invalid template for {} {}: {}: {}", method, path, value, e)
+                            });
+                            headers.insert(name, value);
+                        }
+                        None => template.headers.push((name, value)),
+                    }
                 }
                 TestParameter::Path(s) => {
                     // Join onto the url
+                    let segment = parse(s);
                     let mut seg = url.path_segments_mut().expect("URL Cannot be base");
-                    seg.push(s);
+                    seg.push(&segment.constant().unwrap_or_else(|| s.clone()));
+                    segments.push(segment);
                 }
                 TestParameter::Query { name, value } => {
-                    url.query_pairs_mut().append_pair(name, value);
+                    query.push((name.clone(), parse(value)));
                 }
             }
         }
+        for (name, value) in &query {
+            let value = value
+                .constant()
+                .unwrap_or_else(|| value.source().to_string());
+            url.query_pairs_mut().append_pair(name, &value);
+        }
+        let url_template = UrlTemplate::new(base_uri, path_template.clone(), segments, query);
+        if !url_template.is_constant() {
+            template.url = Some(url_template);
+        }
+        if v.body.is_some() && generated.body.is_some() {
+            // The generated content type is for the generated body
+            headers.remove(CONTENT_TYPE);
+        }
 
         let mut reqs = if let Some(b) = &v.body {
             match b {
                 TestBody::Constant(s) => {
+                    let body = parse(s);
+                    let bytes = Bytes::from(body.constant().unwrap_or_else(|| s.clone()));
+                    if body.constant().is_none() {
+                        template.body = Some(body);
+                    }
                     vec![RequestBuilder {
                         url: url.clone(),
                         method: method.clone(),
                         headers,
-                        body: Bytes::from(s.clone()),
+                        body: BodySource::Bytes(bytes),
+                        pacing: None,
+                        expect: None,
+                        data_name: None,
+                        template: None,
                     }]
                 }
-                TestBody::External(p) => bodies_from_path(&p)
-                    .iter()
+                TestBody::External(p) => bodies_from_path(p)
+                    .into_iter()
                     .map(|body| RequestBuilder {
                         url: url.clone(),
                         method: method.clone(),
                         headers: headers.clone(),
-                        body: body.clone(),
+                        body,
+                        pacing: None,
+                        expect: None,
+                        data_name: None,
+                        template: None,
+                    })
+                    .collect(),
+                TestBody::Multipart(m) => multipart_bodies(m)
+                    .into_iter()
+                    .map(|body| {
+                        let mut headers = headers.clone();
+                        headers.insert(CONTENT_TYPE, body.content_type());
+                        RequestBuilder {
+                            url: url.clone(),
+                            method: method.clone(),
+                            headers,
+                            body: BodySource::Multipart(body),
+                            pacing: None,
+                            expect: None,
+                            data_name: None,
+                            template: None,
+                        }
                     })
                     .collect(),
             }
@@ -149,9 +1060,34 @@ fn requests_from_operation(
                 url: url.clone(),
                 method: method.clone(),
                 headers,
-                body: Bytes::new(),
+                body: BodySource::Bytes(generated.body.clone().unwrap_or_default()),
+                pacing: None,
+                expect: None,
+                data_name: None,
+                template: None,
             }]
         };
+        if let Some(pacing) = v.pacing.as_ref() {
+            for req in reqs.iter_mut() {
+                req.pacing = paced_upload(pacing, &req.body);
+            }
+        }
+        let expect = match v.expect.as_ref() {
+            Some(e) => expectations(Some(e)),
+            None => op_expect.clone(),
+        };
+        add_feeders(&mut template);
+        let mut reqs = reqs
+            .into_iter()
+            .map(|req| {
+                RequestBuilder {
+                    expect: expect.clone(),
+                    data_name: Some(data_name.clone()),
+                    ..req
+                }
+                .with_template(template.clone())
+            })
+            .collect::<Vec<_>>();
         for _ in 0..reqs.len() {
             weights.push((op.weight * v.weight) as f64);
         }
@@ -166,39 +1102,539 @@ impl RequestStore {
         let mut requests = vec![];
 
         let base_uri = Url::parse(&url).expect("URL invalid");
+        let gen = ExampleGenerator::new(&spec.components);
+        let feeders = spec
+            .feeders
+            .iter()
+            .map(|(name, feeder)| {
+                let feeder = Feeder::load(name, feeder)
+                    .unwrap_or_else(|e| panic!("Invalid feeder {}: {}", name, e));
+                Arc::new(feeder)
+            })
+            .collect::<Vec<_>>();
         for (name, item) in &spec.paths {
-            let uri = base_uri.join(&name).expect("Invalid method name");
-
-            if let Some(get) = item.get.as_ref() {
-                let (mut w, mut r) = requests_from_operation(uri.clone(), Method::GET, get);
-                weights.append(&mut w);
-                requests.append(&mut r);
-            }
-            if let Some(post) = item.post.as_ref() {
-                let (mut w, mut r) = requests_from_operation(uri.clone(), Method::POST, post);
-                weights.append(&mut w);
-                requests.append(&mut r);
+            let operations = [
+                (Method::GET, &item.get),
+                (Method::PUT, &item.put),
+                (Method::POST, &item.post),
+                (Method::DELETE, &item.delete),
+                (Method::OPTIONS, &item.options),
+                (Method::HEAD, &item.head),
+                (Method::PATCH, &item.patch),
+                (Method::TRACE, &item.trace),
+            ];
+            for (method, op) in operations.iter() {
+                if let Some(op) = op.as_ref() {
+                    let (mut w, mut r) = requests_from_operation(
+                        &base_uri,
+                        name,
+                        &item.parameters,
+                        method.clone(),
+                        op,
+                        &gen,
+                        &feeders,
+                    );
+                    weights.append(&mut w);
+                    requests.append(&mut r);
+                }
             }
         }
-        Self { weights, requests }
+        let mut store = Self::new(requests, weights);
+        for (name, scenario) in &spec.scenarios {
+            let scenario = Scenario::new(&base_uri, name, scenario, &feeders)
+                .unwrap_or_else(|e| panic!("Invalid scenario {}: {}", name, e));
+            store.scenarios.push(scenario);
+        }
+        store.feeders = feeders;
+        store
     }
 
-    pub fn get_request(&self) -> &RequestBuilder {
-        self.get_requests(1).remove(0)
+    pub fn new(requests: Vec<RequestBuilder>, weights: Vec<f64>) -> Self {
+        // The names used in reports are numbered as different requests can share a method, path
+        // and files. The numbers are padded so they sort in order
+        let width = requests.len().saturating_sub(1).to_string().len();
+        let infos = requests
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                Arc::new(r.info(format!("{:0width$}: {}", i, r.name(), width = width).into()))
+            })
+            .collect();
+        Self {
+            weights,
+            requests,
+            infos,
+            scenarios: vec![],
+            feeders: vec![],
+            cursor: AtomicUsize::new(0),
+        }
     }
 
-    pub fn get_requests(&self, samples: usize) -> Vec<&RequestBuilder> {
-        assert_ne!(samples, 0, "Samples must be >0");
-        assert!(!self.requests.is_empty(), "No request data");
-        assert!(
-            self.weights.len() == self.requests.len(),
-            "Weights vector must match the requests vector"
-        );
+    /// Replaces the requests with ones made by applying each override to a request in the store,
+    /// the first one unless the override gives an index
+    pub fn with_overrides(self, overrides: &[RequestOverride]) -> Result<Self, String> {
+        let requests = overrides
+            .iter()
+            .map(|o| {
+                let index = o.index.unwrap_or_default();
+                let req = self
+                    .requests
+                    .get(index)
+                    .ok_or_else(|| format!("No request {}", index))?;
+                o.apply(req)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let weights = vec![1.0; requests.len()];
+        let mut store = Self::new(requests, weights);
+        store.scenarios = self.scenarios;
+        store.feeders = self.feeders;
+        Ok(store)
+    }
+
+    pub fn selector(&self, mode: SelectionMode) -> RequestSelector<'_> {
+        let weights = match mode {
+            SelectionMode::Weighted => WeightedIndex::new(&self.weights).ok(),
+            _ => None,
+        };
+        RequestSelector {
+            store: self,
+            mode,
+            weights,
+            order: vec![],
+            position: 0,
+        }
+    }
+
+    /// Starts the feeders from their first rows again
+    pub fn reset_feeders(&self) {
+        for feeder in &self.feeders {
+            feeder.reset();
+        }
+    }
 
-        random_choice().random_choice_f64(&self.requests, &self.weights, samples)
+    /// Description of the request at the index
+    pub fn info(&self, index: usize) -> Arc<RequestInfo> {
+        self.infos[index].clone()
     }
 
     pub fn len(&self) -> usize {
         self.requests.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_from_schema() {
+        let spec: Specification = serde_yaml::from_str(
+            r##"
+            paths:
+              users/{id}:
+                put:
+                  parameters:
+                    - in: path
+                      name: id
+                      schema:
+                        type: integer
+                        example: 42
+                    - in: query
+                      name: verbose
+                      required: true
+                      schema:
+                        type: boolean
+                    - in: query
+                      name: optional
+                      schema:
+                        type: string
+                    - $ref: "#/components/parameters/RequestId"
+                  requestBody:
+                    content:
+                      application/json:
+                        schema:
+                          $ref: "#/components/schemas/User"
+            components:
+              parameters:
+                RequestId:
+                  in: header
+                  name: X-Request-ID
+                  required: true
+                  schema:
+                    type: string
+                    format: uuid
+              schemas:
+                User:
+                  type: object
+                  properties:
+                    name:
+                      type: string
+                      default: murk
+        "##,
+        )
+        .unwrap();
+
+        let store = RequestStore::create_from_spec("http://localhost:8080/".to_string(), &spec);
+        assert_eq!(store.len(), 1);
+        let req = store.requests[0].request();
+        assert_eq!(req.method(), Method::PUT);
+        assert_eq!(
+            req.uri().to_string(),
+            "http://localhost:8080/users/42?verbose=false"
+        );
+        assert_eq!(
+            req.headers()["X-Request-ID"],
+            "00000000-0000-0000-0000-000000000000"
+        );
+        assert_eq!(req.headers()[CONTENT_TYPE], "application/json");
+        match &store.requests[0].body {
+            BodySource::Bytes(b) => assert_eq!(b, &Bytes::from(r#"{"name":"murk"}"#)),
+            b => panic!("Expected a body in memory, got {:?}", b),
+        }
+    }
+
+    #[test]
+    fn path_item_parameters() {
+        let spec: Specification = serde_yaml::from_str(
+            r##"
+            paths:
+              users/{id}:
+                parameters:
+                  - in: path
+                    name: id
+                    schema:
+                      type: integer
+                      example: 1
+                  - in: query
+                    name: page
+                    required: true
+                    schema:
+                      type: integer
+                      example: 3
+                get:
+                  parameters:
+                    - in: path
+                      name: id
+                      schema:
+                        type: integer
+                        example: 42
+                    - in: header
+                      name: X-Name
+                      required: true
+                      example: "two\nlines"
+                      schema:
+                        type: string
+                    - in: cookie
+                      name: session
+                      required: true
+                      example: "a;b"
+                      schema:
+                        type: string
+              items/{missing}:
+                get:
+                  parameters:
+                    - in: path
+                      name: missing
+                      schema:
+                        $ref: "#/components/schemas/Missing"
+              tags/{tag}:
+                get:
+                  parameters:
+                    - in: path
+                      name: tag
+                      schema:
+                        type: string
+                        example: "{{uuid}}"
+        "##,
+        )
+        .unwrap();
+
+        let store = RequestStore::create_from_spec("http://localhost:8080/".to_string(), &spec);
+        assert_eq!(store.len(), 2);
+        let req = store.requests[0].request();
+        assert_eq!(
+            req.uri().to_string(),
+            "http://localhost:8080/users/42?page=3"
+        );
+        assert!(!req.headers().contains_key("X-Name"));
+        assert!(!req.headers().contains_key(COOKIE));
+        assert_eq!(
+            store.requests[1].request().uri().to_string(),
+            "http://localhost:8080/tags/%7B%7Buuid%7D%7D"
+        );
+    }
+
+    #[test]
+    fn multipart_folders_take_turns() {
+        let dir = std::env::temp_dir().join("murk_multipart_folders_take_turns");
+        for (folder, files) in [("a", 2), ("b", 3)] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+            for i in 0..files {
+                fs::write(dir.join(folder).join(format!("{}.txt", i)), b"data").unwrap();
+            }
+        }
+        let body = MultipartBody {
+            fields: vec![],
+            files: ["a", "b"]
+                .iter()
+                .map(|folder| FormFile {
+                    name: folder.to_string(),
+                    path: dir.join(folder),
+                    content_type: None,
+                })
+                .collect(),
+        };
+        let bodies = multipart_bodies(&body);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(bodies.len(), 3);
+        let mut b_files = bodies
+            .iter()
+            .map(|b| b.files[1].filename.clone())
+            .collect::<Vec<_>>();
+        b_files.sort();
+        assert_eq!(b_files, vec!["0.txt", "1.txt", "2.txt"]);
+    }
+
+    #[tokio::test]
+    async fn multipart_from_folder() {
+        let dir = std::env::temp_dir().join("murk_multipart_from_folder");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.wav"), b"first").unwrap();
+        fs::write(dir.join("b.txt"), b"second").unwrap();
+
+        let body = MultipartBody {
+            fields: vec![FormField {
+                name: "language".to_string(),
+                value: "en".to_string(),
+            }],
+            files: vec![FormFile {
+                name: "file".to_string(),
+                path: dir.clone(),
+                content_type: None,
+            }],
+        };
+        let mut bodies = vec![];
+        for source in multipart_bodies(&body) {
+            let content_type = source.content_type().to_str().unwrap().to_string();
+            let len = source.len;
+            let body = hyper::body::to_bytes(Body::from(BodySource::Multipart(source)))
+                .await
+                .unwrap();
+            assert_eq!(body.len(), len);
+            bodies.push((content_type, String::from_utf8(body.to_vec()).unwrap()));
+        }
+        bodies.sort_by_key(|(_, body)| !body.contains("a.wav"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(bodies.len(), 2);
+        for (content_type, body) in &bodies {
+            let boundary = content_type
+                .strip_prefix("multipart/form-data; boundary=")
+                .unwrap();
+            assert!(body.starts_with(&format!("--{}\r\n", boundary)));
+            assert!(body.ends_with(&format!("--{}--\r\n", boundary)));
+            assert!(body.contains("name=\"language\"\r\n\r\nen\r\n"));
+        }
+        assert!(bodies[0]
+            .1
+            .contains("filename=\"a.wav\"\r\nContent-Type: audio/wav"));
+        assert!(bodies[0].1.contains("\r\n\r\nfirst\r\n"));
+        assert!(bodies[1]
+            .1
+            .contains("filename=\"b.txt\"\r\nContent-Type: text/plain"));
+    }
+
+    #[tokio::test]
+    async fn stream_file_body() {
+        let path = std::env::temp_dir().join("murk_stream_file_body");
+        let data = (0..CHUNK_SIZE * 3 + 7)
+            .map(|x| (x % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(&path, &data).unwrap();
+
+        let bodies = bodies_from_path(&path);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].len(), data.len());
+        let body = hyper::body::to_bytes(Body::from(bodies[0].clone()))
+            .await
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(body.as_ref(), data.as_slice());
+    }
+
+    #[test]
+    fn wav_rate() {
+        let mut header = b"RIFF\0\0\0\0WAVE".to_vec();
+        // A list chunk before the format chunk with an odd length
+        header.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+        header.extend_from_slice(b"fmt \x10\0\0\0");
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&2u16.to_le_bytes()); // channels
+        header.extend_from_slice(&16000u32.to_le_bytes()); // sample rate
+        header.extend_from_slice(&64000u32.to_le_bytes()); // byte rate
+        header.extend_from_slice(&4u16.to_le_bytes()); // block align
+        header.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+
+        assert_eq!(wav_byte_rate(&header), Some(64000));
+        assert_eq!(wav_byte_rate(b"not a wav file"), None);
+    }
+
+    #[tokio::test]
+    async fn paced_body() {
+        let pacing = PacedUpload {
+            bytes_per_second: 1000,
+            interval: Duration::from_millis(20),
+        };
+        let timer = UploadTimer::default();
+        let body = BodySource::Bytes(Bytes::from(vec![0u8; 50]));
+        let start = Instant::now();
+        let chunks = paced_stream(body.stream(), pacing, timer.clone())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+
+        let lens = chunks.iter().map(|c| c.len()).collect::<Vec<_>>();
+        assert_eq!(lens, vec![20, 20, 10]);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(timer.finished().unwrap() >= start + Duration::from_millis(40));
+
+        let mut req = RequestBuilder::try_from("http://localhost/upload".to_string()).unwrap();
+        req.body = BodySource::File {
+            path: PathBuf::from("audio.wav"),
+            len: 50,
+        };
+        assert_eq!(req.request().headers()[CONTENT_LENGTH], "50");
+        req.pacing = Some(pacing);
+        assert!(req.request().headers().get(CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn selection_modes() {
+        let requests = [
+            "http://localhost/a",
+            "http://localhost/b",
+            "http://localhost/c",
+        ]
+        .iter()
+        .map(|u| RequestBuilder::try_from(u.to_string()).unwrap())
+        .collect();
+        let store = RequestStore::new(requests, vec![0.0, 1.0, 0.0]);
+        assert_eq!(store.info(1).name.as_ref(), "1: GET /b");
+        let padded = RequestStore::new(vec![store.requests[0].clone(); 11], vec![1.0; 11]);
+        assert_eq!(padded.info(2).name.as_ref(), "02: GET /a");
+
+        assert!(store
+            .selector(SelectionMode::Weighted)
+            .take(20)
+            .all(|i| i == 1));
+
+        let mut sequential = store.selector(SelectionMode::Sequential);
+        assert_eq!(
+            sequential.by_ref().take(4).collect::<Vec<_>>(),
+            vec![0, 1, 2, 0]
+        );
+        // The position is shared between users
+        assert_eq!(store.selector(SelectionMode::Sequential).next(), Some(1));
+
+        let shuffled = store
+            .selector(SelectionMode::Shuffle)
+            .take(6)
+            .collect::<Vec<_>>();
+        for pass in shuffled.chunks(3) {
+            let mut pass = pass.to_vec();
+            pass.sort_unstable();
+            assert_eq!(pass, vec![0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn apply_override() {
+        let req = RequestBuilder::try_from("http://localhost/api/v1/jobs".to_string()).unwrap();
+        let change = RequestOverride {
+            method: Some("post".to_string()),
+            url: Some("jobs/42?sig=abc".to_string()),
+            headers: vec![("x-request-id".to_string(), "1".to_string())],
+            body: Some(Bytes::from_static(b"hello")),
+            ..Default::default()
+        };
+        let req = change.apply(&req).unwrap();
+        assert_eq!(req.name(), "POST /api/v1/jobs/42?sig=abc");
+        assert_eq!(req.headers["x-request-id"], "1");
+        assert_eq!(req.body_len(), 5);
+        assert!(req.expect.is_none());
+
+        let expect = Arc::new(Expectations::new(&Default::default()).unwrap());
+        let mut req = RequestBuilder::try_from("http://localhost/".to_string()).unwrap();
+        req.expect = Some(expect);
+        let headers_only = RequestOverride {
+            headers: vec![("x-request-id".to_string(), "2".to_string())],
+            ..Default::default()
+        };
+        assert!(headers_only.apply(&req).unwrap().expect.is_some());
+
+        let bad = RequestOverride {
+            headers: vec![("bad header".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+        assert!(bad.apply(&req).is_err());
+    }
+
+    #[test]
+    fn templated_requests() {
+        let spec: Specification = serde_yaml::from_str(
+            r#"
+            paths:
+              /users/{{counter}}:
+                post:
+                  requestData:
+                    new:
+                      parameters:
+                        - header:
+                            name: X-Request-ID
+                            value: "{{uuid}}"
+                        - header:
+                            name: X-Static
+                            value: fixed
+                        - query:
+                            name: n
+                            value: "{{random_int 5 5}}"
+                      body:
+                        constant: '{"id": "{{counter}}"}'
+        "#,
+        )
+        .unwrap();
+        let store = RequestStore::create_from_spec("http://localhost:8080/".to_string(), &spec);
+        let template = &store.requests[0];
+        assert_eq!(
+            template.name(),
+            "POST /users/{{counter}}?n={{random_int 5 5}}"
+        );
+        assert_eq!(template.headers["X-Static"], "fixed");
+
+        let vars = Variables::new();
+        let first = template.render(&vars).unwrap().into_owned();
+        let second = template.render(&vars).unwrap().into_owned();
+        assert_ne!(
+            first.headers["X-Request-ID"],
+            second.headers["X-Request-ID"]
+        );
+        let counter = first
+            .url
+            .path_segments()
+            .unwrap()
+            .next_back()
+            .unwrap()
+            .to_string();
+        assert_eq!(first.url.query(), Some("n=5"));
+        match &first.body {
+            BodySource::Bytes(b) => assert_eq!(b, &format!(r#"{{"id": "{}"}}"#, counter)),
+            _ => panic!("Expected a constant body"),
+        }
+        assert_ne!(first.url, second.url);
+    }
 }
@@ -0,0 +1,302 @@
+//! Scenarios are sequences of requests a user sends in order, like logging in and then using the
+//! token or creating a job and then polling its status. Values can be extracted from a response
+//! into variables and used in later steps with `{{name}}`, along with the other template
+//! expressions.
+use crate::expect::Expectations;
+use crate::feeder::{feeders_for, Feeder};
+use crate::request::*;
+use crate::spec::{self, Extract, TestBody, TestParameter};
+use crate::summary::RequestStats;
+use crate::template::{Template, UrlTemplate, Variables};
+use bytes::Bytes;
+use hyper::header::{HeaderName, CONTENT_TYPE};
+use hyper::{HeaderMap, Method};
+use rand::seq::SliceRandom;
+use regex::Regex;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+pub struct Scenario {
+    pub name: Arc<str>,
+    pub weight: usize,
+    pub steps: Vec<ScenarioStep>,
+    /// Feeders used in the steps, a row is taken from each at the start of every run
+    pub feeders: Vec<Arc<Feeder>>,
+}
+
+enum Extractor {
+    Json(String),
+    Header(HeaderName),
+    Regex(Regex),
+}
+
+pub struct ScenarioStep {
+    /// Name in reports, prefixed with the scenario name
+    pub name: Arc<str>,
+    scenario: Arc<str>,
+    /// One request for each body, with the templates to fill in from the variables
+    requests: Vec<RequestBuilder>,
+    extract: Vec<(String, Extractor)>,
+    until: Option<Expectations>,
+    pub poll_interval: Duration,
+    pub max_polls: usize,
+}
+
+impl Scenario {
+    pub fn new(
+        base: &Url,
+        name: &str,
+        scenario: &spec::Scenario,
+        feeders: &[Arc<Feeder>],
+    ) -> Result<Self, String> {
+        let name: Arc<str> = name.into();
+        let steps = scenario
+            .steps
+            .iter()
+            .map(|step| ScenarioStep::new(base, name.clone(), step))
+            .collect::<Result<Vec<_>, _>>()?;
+        let vars = steps
+            .iter()
+            .flat_map(|s| s.requests.iter().flat_map(|r| r.variables()));
+        let feeders = feeders_for(feeders, vars);
+        Ok(Self {
+            name,
+            weight: scenario.weight,
+            steps,
+            feeders,
+        })
+    }
+}
+
+impl ScenarioStep {
+    fn new(base: &Url, scenario: Arc<str>, step: &spec::Step) -> Result<Self, String> {
+        let method = Method::from_bytes(step.method.to_uppercase().as_bytes())
+            .map_err(|e| format!("Invalid method {}: {}", step.method, e))?;
+        let step_name = match step.name.as_ref() {
+            Some(name) => name.clone(),
+            None => format!("{} {}", method, step.path),
+        };
+        let mut template = RequestTemplate::default();
+        let mut segments = vec![];
+        let mut query = vec![];
+        for param in &step.parameters {
+            match param {
+                TestParameter::Header { name, value } => {
+                    let name = HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+                    template.headers.push((name, Template::parse(value)?));
+                }
+                TestParameter::Path(s) => segments.push(Template::parse(s)?),
+                TestParameter::Query { name, value } => {
+                    query.push((name.clone(), Template::parse(value)?))
+                }
+            }
+        }
+        template.url = Some(UrlTemplate::new(
+            base,
+            Template::parse(&step.path)?,
+            segments,
+            query,
+        ));
+        let bodies = match step.body.as_ref() {
+            None => vec![(BodySource::Bytes(Bytes::new()), None)],
+            Some(TestBody::Constant(s)) => {
+                template.body = Some(Template::parse(s)?);
+                vec![(BodySource::Bytes(Bytes::new()), None)]
+            }
+            Some(TestBody::External(path)) => bodies_from_path(path)
+                .into_iter()
+                .map(|b| (b, None))
+                .collect(),
+            Some(TestBody::Multipart(m)) => multipart_bodies(m)
+                .into_iter()
+                .map(|b| {
+                    let content_type = b.content_type();
+                    (BodySource::Multipart(b), Some(content_type))
+                })
+                .collect(),
+        };
+        if bodies.is_empty() {
+            return Err(format!("No files found for step {}", step_name));
+        }
+        let expect = match step.expect.as_ref() {
+            Some(e) => Some(Arc::new(Expectations::new(e)?)),
+            None => None,
+        };
+        let requests = bodies
+            .into_iter()
+            .map(|(body, content_type)| {
+                let mut headers = HeaderMap::new();
+                if let Some(content_type) = content_type {
+                    headers.insert(CONTENT_TYPE, content_type);
+                }
+                RequestBuilder::new(base.clone(), method.clone(), headers, body)
+                    .with_expectations(expect.clone())
+                    .with_template(template.clone())
+            })
+            .collect();
+        let mut extract = vec![];
+        for (var, source) in &step.extract {
+            let extractor = match source {
+                Extract::Json(pointer) => Extractor::Json(pointer.clone()),
+                Extract::Header(name) => Extractor::Header(
+                    HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|e| format!("Invalid header name {}: {}", name, e))?,
+                ),
+                Extract::Regex(re) => Extractor::Regex(Regex::new(re).map_err(|e| e.to_string())?),
+            };
+            extract.push((var.clone(), extractor));
+        }
+        Ok(Self {
+            name: format!("{}: {}", scenario, step_name).into(),
+            scenario,
+            requests,
+            extract,
+            until: match step.until.as_ref() {
+                Some(e) => Some(Expectations::new(e)?),
+                None => None,
+            },
+            poll_interval: Duration::from_millis(step.poll_interval_ms),
+            max_polls: step.max_polls.max(1),
+        })
+    }
+
+    /// Creates the request filling in any variables
+    pub fn build(&self, vars: &Variables) -> Result<RequestBuilder, String> {
+        let req = self.requests.choose(&mut rand::thread_rng()).unwrap();
+        req.render(vars).map(|req| req.into_owned())
+    }
+
+    pub fn info(&self, req: &RequestBuilder) -> RequestInfo {
+        let mut info = req.info(self.name.clone());
+        info.scenario = Some(self.scenario.clone());
+        info
+    }
+
+    /// Whether the response passes the `until` checks, and so polling can stop
+    pub fn finished_polling(&self, stats: &RequestStats) -> bool {
+        let until = match self.until.as_ref() {
+            Some(until) => until,
+            None => return true,
+        };
+        match (stats.status, stats.request_time) {
+            (Some(status), Some(time)) => {
+                let body = stats.body.clone().unwrap_or_default();
+                until
+                    .check(status, response_headers(stats), &body, time)
+                    .is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    /// Sets the variables taken from the response, returning the names of any which couldn't be
+    /// found
+    pub fn extract(&self, stats: &RequestStats, vars: &mut Variables) -> Vec<String> {
+        let body = stats.body.clone().unwrap_or_default();
+        let mut json = None;
+        let mut missing = vec![];
+        for (var, extractor) in &self.extract {
+            let value = match extractor {
+                Extractor::Json(pointer) => json
+                    .get_or_insert_with(|| serde_json::from_slice::<Value>(&body).ok())
+                    .as_ref()
+                    .and_then(|j| j.pointer(pointer))
+                    .map(|v| match v {
+                        Value::String(s) => s.clone(),
+                        v => v.to_string(),
+                    }),
+                Extractor::Header(name) => response_headers(stats)
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string()),
+                Extractor::Regex(re) => {
+                    let text = String::from_utf8_lossy(&body);
+                    re.captures(&text).map(|c| {
+                        c.get(1)
+                            .or_else(|| c.get(0))
+                            .map(|m| m.as_str().to_string())
+                            .unwrap_or_default()
+                    })
+                }
+            };
+            match value {
+                Some(value) => {
+                    vars.insert(var.clone(), value);
+                }
+                None => missing.push(var.clone()),
+            }
+        }
+        missing
+    }
+}
+
+/// Headers of a response to a step, which are always kept for scenarios
+fn response_headers(stats: &RequestStats) -> &HeaderMap {
+    stats
+        .headers
+        .as_ref()
+        .expect("Headers should be kept for every response to a scenario step")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use hyper::StatusCode;
+
+    #[test]
+    fn build_and_extract() {
+        let step: spec::Step = serde_yaml::from_str(
+            r#"
+            method: post
+            path: /jobs/{{id}}/status
+            parameters:
+              - header:
+                  name: authorization
+                  value: Bearer {{token}}
+            body:
+              constant: '{"job": "{{id}}"}'
+            extract:
+              state:
+                json: /state
+              location:
+                header: location
+              count:
+                regex: 'count=(\d+)'
+              missing:
+                json: /nothing
+        "#,
+        )
+        .unwrap();
+        let base = Url::parse("http://localhost/api/").unwrap();
+        let step = ScenarioStep::new(&base, "job".into(), &step).unwrap();
+        assert_eq!(step.name.as_ref(), "job: POST /jobs/{{id}}/status");
+
+        let mut vars = Variables::new();
+        vars.insert("id".to_string(), "7".to_string());
+        vars.insert("token".to_string(), "abc".to_string());
+        let req = step.build(&vars).unwrap();
+        let info = step.info(&req);
+        assert_eq!(info.url, "http://localhost/jobs/7/status");
+        assert_eq!(info.method, "POST");
+        assert_eq!(info.body_len, 12);
+        assert_eq!(info.scenario.as_deref(), Some("job"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("location", HeaderValue::from_static("/jobs/7"));
+        let stats = RequestStats {
+            status: Some(StatusCode::OK),
+            headers: Some(headers),
+            body: Some(Bytes::from_static(br#"{"state": "done", "count=3": 1}"#)),
+            ..Default::default()
+        };
+        let missing = step.extract(&stats, &mut vars);
+        assert_eq!(missing, vec!["missing"]);
+        assert_eq!(vars["state"], "done");
+        assert_eq!(vars["location"], "/jobs/7");
+        assert_eq!(vars["count"], "3");
+    }
+}
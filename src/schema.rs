@@ -0,0 +1,258 @@
+//! Creates example values from the OpenAPI parameters and schemas in a specification. This lets
+//! murk make requests for an operation without any hand-written `requestData`. Values are taken
+//! from the example, default or enum values given in the schema where possible, and otherwise a
+//! plausible value is made up from the type.
+use openapiv3::*;
+use serde_json::{Map, Number, Value};
+
+/// Stops us recursing forever on self-referential schemas
+const MAX_DEPTH: usize = 8;
+
+fn resolve<'a, T>(
+    mut item: &'a ReferenceOr<T>,
+    components: &'a indexmap::IndexMap<String, ReferenceOr<T>>,
+    prefix: &str,
+) -> Option<&'a T> {
+    for _ in 0..MAX_DEPTH {
+        match item {
+            ReferenceOr::Item(t) => return Some(t),
+            ReferenceOr::Reference { reference } => {
+                let name = reference.strip_prefix(prefix)?;
+                item = components.get(name)?;
+            }
+        }
+    }
+    None
+}
+
+pub struct ExampleGenerator<'a> {
+    components: &'a Components,
+}
+
+impl<'a> ExampleGenerator<'a> {
+    pub fn new(components: &'a Components) -> Self {
+        Self { components }
+    }
+
+    pub fn parameter(&self, param: &'a ReferenceOr<Parameter>) -> Option<&'a Parameter> {
+        resolve(
+            param,
+            &self.components.parameters,
+            "#/components/parameters/",
+        )
+    }
+
+    pub fn request_body(&self, body: &'a ReferenceOr<RequestBody>) -> Option<&'a RequestBody> {
+        resolve(
+            body,
+            &self.components.request_bodies,
+            "#/components/requestBodies/",
+        )
+    }
+
+    pub fn schema(&self, schema: &'a ReferenceOr<Schema>) -> Option<&'a Schema> {
+        resolve(schema, &self.components.schemas, "#/components/schemas/")
+    }
+
+    fn boxed_schema(&self, schema: &'a ReferenceOr<Box<Schema>>) -> Option<&'a Schema> {
+        match schema {
+            ReferenceOr::Item(s) => Some(s),
+            ReferenceOr::Reference { reference } => {
+                let name = reference.strip_prefix("#/components/schemas/")?;
+                self.schema(self.components.schemas.get(name)?)
+            }
+        }
+    }
+
+    /// Value for a parameter formatted for use in a header, path or query string
+    pub fn parameter_value(&self, data: &'a ParameterData) -> Option<String> {
+        let value = match &data.example {
+            Some(v) => v.clone(),
+            None => match &data.format {
+                ParameterSchemaOrContent::Schema(s) => self.value(self.schema(s)?, 0)?,
+                ParameterSchemaOrContent::Content(c) => {
+                    let media = c.values().next()?;
+                    self.media_value(media)?
+                }
+            },
+        };
+        match value {
+            Value::String(s) => Some(s),
+            Value::Array(a) => {
+                // Simple style, comma separated values
+                let values = a.iter().map(value_to_string).collect::<Vec<_>>();
+                Some(values.join(","))
+            }
+            v => Some(v.to_string()),
+        }
+    }
+
+    /// Value for the body of a media type, if there's no example we need a schema to make one
+    pub fn media_value(&self, media: &'a MediaType) -> Option<Value> {
+        match &media.example {
+            Some(v) => Some(v.clone()),
+            None => self.value(self.schema(media.schema.as_ref()?)?, 0),
+        }
+    }
+
+    pub fn value(&self, schema: &'a Schema, depth: usize) -> Option<Value> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let data = &schema.schema_data;
+        if let Some(v) = data.example.as_ref().or(data.default.as_ref()) {
+            return Some(v.clone());
+        }
+        match &schema.schema_kind {
+            SchemaKind::Type(ty) => self.type_value(ty, depth),
+            SchemaKind::OneOf { one_of: schemas } | SchemaKind::AnyOf { any_of: schemas } => {
+                let schema = self.schema(schemas.first()?)?;
+                self.value(schema, depth + 1)
+            }
+            SchemaKind::AllOf { all_of } => {
+                // Merge the objects together, if they aren't objects just take the last value
+                let mut res: Option<Value> = None;
+                for schema in all_of.iter().filter_map(|s| self.schema(s)) {
+                    let value = self.value(schema, depth + 1)?;
+                    match (res.as_mut(), value) {
+                        (Some(Value::Object(res)), Value::Object(value)) => res.extend(value),
+                        (_, value) => res = Some(value),
+                    }
+                }
+                res
+            }
+            SchemaKind::Any(any) => {
+                if !any.properties.is_empty() {
+                    self.object_value(&any.properties, depth)
+                } else if let Some(items) = any.items.as_ref() {
+                    let item = self.value(self.boxed_schema(items)?, depth + 1)?;
+                    Some(Value::Array(vec![item; any.min_items.unwrap_or(1)]))
+                } else {
+                    Some(Value::Null)
+                }
+            }
+        }
+    }
+
+    fn type_value(&self, ty: &'a Type, depth: usize) -> Option<Value> {
+        let value = match ty {
+            Type::String(s) => match s.enumeration.first() {
+                Some(e) => Value::String(e.clone()),
+                None => Value::String(string_value(s)),
+            },
+            Type::Number(n) => {
+                let num = match n.enumeration.first() {
+                    Some(e) => *e,
+                    None => n.minimum.or(n.maximum).unwrap_or_default(),
+                };
+                Number::from_f64(num)
+                    .map(Value::Number)
+                    .unwrap_or(Value::Null)
+            }
+            Type::Integer(i) => match i.enumeration.first() {
+                Some(e) => Value::from(*e),
+                None => Value::from(i.minimum.or(i.maximum).unwrap_or_default()),
+            },
+            Type::Boolean {} => Value::Bool(false),
+            Type::Object(o) => return self.object_value(&o.properties, depth),
+            Type::Array(a) => {
+                let item = self.value(self.boxed_schema(&a.items)?, depth + 1)?;
+                Value::Array(vec![item; a.min_items.unwrap_or(1)])
+            }
+        };
+        Some(value)
+    }
+
+    fn object_value(
+        &self,
+        properties: &'a indexmap::IndexMap<String, ReferenceOr<Box<Schema>>>,
+        depth: usize,
+    ) -> Option<Value> {
+        let mut map = Map::new();
+        for (name, prop) in properties {
+            if let Some(schema) = self.boxed_schema(prop) {
+                if schema.schema_data.read_only {
+                    continue;
+                }
+                if let Some(value) = self.value(schema, depth + 1) {
+                    map.insert(name.clone(), value);
+                }
+            }
+        }
+        Some(Value::Object(map))
+    }
+}
+
+fn string_value(s: &StringType) -> String {
+    let value = match &s.format {
+        VariantOrUnknownOrEmpty::Item(StringFormat::Date) => "2021-01-01",
+        VariantOrUnknownOrEmpty::Item(StringFormat::DateTime) => "2021-01-01T00:00:00Z",
+        VariantOrUnknownOrEmpty::Unknown(f) if f == "uuid" => {
+            "00000000-0000-0000-0000-000000000000"
+        }
+        VariantOrUnknownOrEmpty::Unknown(f) if f == "email" => "murk@example.com",
+        VariantOrUnknownOrEmpty::Unknown(f) if f == "uri" => "http://example.com",
+        _ => "string",
+    };
+    let mut value = value.to_string();
+    if let Some(min) = s.min_length {
+        while value.len() < min {
+            value.push('a');
+        }
+    }
+    value
+}
+
+fn value_to_string(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn object_from_schema() {
+        let components: Components = serde_yaml::from_str(
+            r#"
+            schemas:
+              Job:
+                type: object
+                properties:
+                  id:
+                    type: string
+                    readOnly: true
+                  name:
+                    type: string
+                    example: transcribe
+                  priority:
+                    type: integer
+                    minimum: 1
+                  mode:
+                    type: string
+                    enum: [fast, accurate]
+                  tags:
+                    type: array
+                    items:
+                      type: string
+        "#,
+        )
+        .unwrap();
+        let gen = ExampleGenerator::new(&components);
+        let job = ReferenceOr::ref_("#/components/schemas/Job");
+        let schema = gen.schema(&job).unwrap();
+        assert_eq!(
+            gen.value(schema, 0),
+            Some(json!({
+                "name": "transcribe",
+                "priority": 1,
+                "mode": "fast",
+                "tags": ["string"],
+            }))
+        );
+    }
+}
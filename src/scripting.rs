@@ -1,37 +1,84 @@
+use crate::request::RequestOverride;
 use crate::summary::*;
-use flume::{Receiver, Sender};
+use bytes::Bytes;
+use flume::{Receiver, RecvError, Selector, Sender};
+use hdrhistogram::Histogram;
 use pyo3::conversion::ToPyObject;
 use pyo3::prelude::*;
 use pyo3::types::*;
+use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::path::Path;
+use std::time::UNIX_EPOCH;
+use tokio::sync::oneshot;
 use tokio::task::{spawn_blocking, JoinHandle};
 
 #[derive(Default)]
 pub struct ScriptingContext {
-    response_tx: Option<Sender<RequestStats>>,
+    response_tx: Option<Sender<ScriptMessage>>,
     output_rx: Option<Receiver<ScriptEvents>>,
+    hook_rx: Option<oneshot::Receiver<ScriptHooks>>,
+    hook: Option<RequestHook>,
+    /// Requests made by the script's `init_requests` function
+    requests: Vec<RequestOverride>,
+    /// Whether the script has a `handle_response` function
+    response_hook: bool,
     handle: Option<JoinHandle<PyResult<()>>>,
 }
 
+/// What the script provides for making requests, sent once it has loaded
+struct ScriptHooks {
+    requests: Vec<RequestOverride>,
+    make_request: Option<RequestHook>,
+    handle_response: bool,
+}
+
 impl ScriptingContext {
     pub fn load(script: impl AsRef<Path>) -> Self {
         let (response_tx, response_rx) = flume::unbounded();
         let (output_tx, output_rx) = flume::unbounded();
+        let (hook_tx, hook_rx) = oneshot::channel();
         let script = script.as_ref().to_path_buf();
-        let handle =
-            spawn_blocking(move || launch_scripting_engine(script, response_rx, output_tx));
+        let handle = spawn_blocking(move || {
+            launch_scripting_engine(script, response_rx, output_tx, hook_tx)
+        });
         Self {
             response_tx: Some(response_tx),
             output_rx: Some(output_rx),
+            hook_rx: Some(hook_rx),
+            hook: None,
+            requests: vec![],
+            response_hook: false,
             handle: Some(handle),
         }
     }
 
+    /// Waits for the script to load so we know whether it has a `make_request` hook and any
+    /// requests it made up front
+    pub async fn load_hook(&mut self) {
+        if let Some(rx) = self.hook_rx.take() {
+            if let Ok(hooks) = rx.await {
+                self.hook = hooks.make_request;
+                self.requests = hooks.requests;
+                self.response_hook = hooks.handle_response;
+            }
+        }
+    }
+
+    /// Takes the requests made by the script's `init_requests` function
+    pub fn take_requests(&mut self) -> Vec<RequestOverride> {
+        std::mem::take(&mut self.requests)
+    }
+
     pub fn empty() -> Self {
         Self::default()
     }
 
+    /// Whether responses need their headers kept for the script's `handle_response` function
+    pub fn has_response_hook(&self) -> bool {
+        self.response_hook
+    }
+
     pub fn is_active(&self) -> bool {
         self.handle.is_some() && self.response_tx.is_some() && self.output_rx.is_some()
     }
@@ -39,6 +86,7 @@ impl ScriptingContext {
     pub async fn finish(self) -> PyResult<()> {
         std::mem::drop(self.response_tx);
         std::mem::drop(self.output_rx);
+        std::mem::drop(self.hook);
         println!("Closing script engine");
         if let Some(hnd) = self.handle {
             hnd.await.unwrap()
@@ -47,9 +95,184 @@ impl ScriptingContext {
         }
     }
 
-    pub fn response_sender(&self) -> Option<Sender<RequestStats>> {
+    pub fn response_sender(&self) -> Option<Sender<ScriptMessage>> {
         self.response_tx.clone()
     }
+
+    pub fn event_receiver(&self) -> Option<Receiver<ScriptEvents>> {
+        self.output_rx.clone()
+    }
+
+    pub fn request_hook(&self) -> Option<RequestHook> {
+        self.hook.clone()
+    }
+}
+
+pub struct RequestHookCall {
+    user_id: usize,
+    reply: oneshot::Sender<Option<RequestOverride>>,
+}
+
+/// Asks the script's `make_request(user_id)` function how to change the next request. The
+/// function can return `None` to send the selected request unchanged, the index of a request to
+/// send instead or a dict with any of `request` (an index), `method`, `url`, `headers` and `body`.
+///
+/// This is a round trip to the script for every request, so requests which don't need to change
+/// each time should be returned as a list in the same form from `init_requests()` instead. Those
+/// are built once when the script loads and replace the requests from the specification
+#[derive(Clone)]
+pub struct RequestHook(Sender<RequestHookCall>);
+
+impl RequestHook {
+    pub async fn make_request(&self, user_id: usize) -> Option<RequestOverride> {
+        let (reply, rx) = oneshot::channel();
+        self.0.send(RequestHookCall { user_id, reply }).ok()?;
+        rx.await.ok().flatten()
+    }
+}
+
+fn extract_override(value: &PyAny) -> PyResult<Option<RequestOverride>> {
+    if value.is_none() {
+        return Ok(None);
+    }
+    if let Ok(index) = value.extract::<usize>() {
+        return Ok(Some(RequestOverride {
+            index: Some(index),
+            ..Default::default()
+        }));
+    }
+    let dict = value.downcast::<PyDict>()?;
+    let mut res = RequestOverride::default();
+    if let Some(index) = dict.get_item("request") {
+        res.index = Some(index.extract()?);
+    }
+    if let Some(method) = dict.get_item("method") {
+        res.method = Some(method.extract()?);
+    }
+    if let Some(url) = dict.get_item("url") {
+        res.url = Some(url.extract()?);
+    }
+    if let Some(headers) = dict.get_item("headers") {
+        res.headers = headers
+            .extract::<HashMap<String, String>>()?
+            .into_iter()
+            .collect();
+    }
+    if let Some(body) = dict.get_item("body") {
+        res.body = Some(match body.extract::<&[u8]>() {
+            Ok(bytes) => Bytes::copy_from_slice(bytes),
+            Err(_) => Bytes::from(body.extract::<String>()?),
+        });
+    }
+    Ok(Some(res))
+}
+
+/// Passed to a script's `handle_response(response)` function for every request, including ones
+/// which failed or timed out
+#[pyclass]
+struct Response {
+    /// Status code, `None` if no response was received
+    #[pyo3(get)]
+    status: Option<u16>,
+    body: Bytes,
+    /// Response headers with lower case names, repeated headers are joined with commas
+    #[pyo3(get)]
+    headers: HashMap<String, String>,
+    #[pyo3(get)]
+    method: Option<String>,
+    #[pyo3(get)]
+    url: Option<String>,
+    /// Name murk uses for the request in reports
+    #[pyo3(get)]
+    name: Option<String>,
+    /// Name of the requestData entry in the specification
+    #[pyo3(get)]
+    request_data: Option<String>,
+    /// Files the request body was read from
+    #[pyo3(get)]
+    files: Vec<String>,
+    #[pyo3(get)]
+    request_body_length: Option<usize>,
+    /// `timeout` if the request timed out or the kind of error if it failed, otherwise `None`
+    #[pyo3(get)]
+    error: Option<String>,
+    #[pyo3(get)]
+    failed_assertions: Vec<String>,
+    /// Request duration in milliseconds
+    #[pyo3(get)]
+    time: Option<f64>,
+    /// When the request was sent and the response finished as seconds since the Unix epoch
+    #[pyo3(get)]
+    start_time: Option<f64>,
+    #[pyo3(get)]
+    end_time: Option<f64>,
+    #[pyo3(get)]
+    connections: usize,
+}
+
+#[pymethods]
+impl Response {
+    #[getter]
+    fn body(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &self.body).into()
+    }
+}
+
+impl From<&RequestStats> for Response {
+    fn from(stats: &RequestStats) -> Self {
+        let mut headers = HashMap::<String, String>::new();
+        for (name, value) in stats.headers.iter().flatten() {
+            let value = String::from_utf8_lossy(value.as_bytes());
+            headers
+                .entry(name.to_string())
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(&value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+        let error = if stats.timeout {
+            Some("timeout".to_string())
+        } else {
+            stats.error.as_ref().map(|e| e.to_string())
+        };
+        let start_time = stats
+            .start_time
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+        let end_time = start_time.zip(stats.request_time).map(|(s, t)| s + t);
+        let request = stats.request.as_ref();
+        Self {
+            status: stats.status.map(|s| s.as_u16()),
+            body: stats.body.clone().unwrap_or_default(),
+            headers,
+            method: request.map(|r| r.method.clone()),
+            url: request.map(|r| r.url.clone()),
+            name: request.map(|r| r.name.to_string()),
+            request_data: request.and_then(|r| r.data_name.clone()),
+            files: request
+                .map(|r| r.files.iter().map(|f| f.display().to_string()).collect())
+                .unwrap_or_default(),
+            request_body_length: request.map(|r| r.body_len),
+            error,
+            failed_assertions: stats.assertion_failures.clone().unwrap_or_default(),
+            time: stats.request_time.map(|t| 1000.0 * t.as_secs_f64()),
+            start_time: start_time.map(|t| t.as_secs_f64()),
+            end_time: end_time.map(|t| t.as_secs_f64()),
+            connections: stats.connections,
+        }
+    }
+}
+
+/// Sent to the script engine by the stats collector
+pub enum ScriptMessage {
+    Response(Box<RequestStats>),
+    /// Asks for a `ScriptEvents::Flushed` once everything sent before has been handled
+    Flush,
+}
+
+enum ScriptInput {
+    Response(Result<ScriptMessage, RecvError>),
+    Request(Result<RequestHookCall, RecvError>),
 }
 
 #[derive(Debug, Clone)]
@@ -64,34 +287,69 @@ pub enum ScriptEvents {
         name: String,
         value: u64,
     },
+    /// Every event from the responses sent before a `ScriptMessage::Flush` has been sent
+    Flushed,
+}
+
+/// Exposed to scripts as the `murk` module so they can feed values into their custom histograms
+/// via `murk.record(name, value)`
+#[pyclass]
+struct Recorder {
+    outputs: Sender<ScriptEvents>,
+}
+
+#[pymethods]
+impl Recorder {
+    fn record(&self, name: String, value: u64) {
+        let _ = self
+            .outputs
+            .send(ScriptEvents::UpdateHistogram { name, value });
+    }
+}
+
+fn register_murk_module(py: Python, outputs: Sender<ScriptEvents>) -> PyResult<()> {
+    let module = PyModule::new(py, "murk")?;
+    let recorder = Py::new(py, Recorder { outputs })?;
+    module.add("record", recorder.getattr(py, "record")?)?;
+    py.import("sys")?
+        .getattr("modules")?
+        .set_item("murk", module)?;
+    Ok(())
 }
 
 /// This needs to be in a spawn_blocking or something cause this gonna block like hellll
 fn launch_scripting_engine(
     script: impl AsRef<Path>,
-    responses: Receiver<RequestStats>,
+    responses: Receiver<ScriptMessage>,
     outputs: Sender<ScriptEvents>,
+    hook: oneshot::Sender<ScriptHooks>,
 ) -> PyResult<()> {
     let name = script
         .as_ref()
         .file_name()
-        .map(|x| x.to_str())
-        .flatten()
+        .and_then(|x| x.to_str())
         .unwrap_or_default();
     let script_contents = read_to_string(&script)?;
 
     Python::with_gil(move |py| -> PyResult<()> {
+        register_murk_module(py, outputs.clone())?;
         let module = PyModule::from_code(py, &script_contents, name, "murk_script")?;
         if let Ok(init_stats) = module.getattr("init_stats") {
             // Call and send out whatever it is registers new histograms/collectors
-            let histograms: Vec<(String, u64, u64, Option<u8>)> = init_stats
+            let histograms = init_stats
                 .call0()
-                .expect("Failed to call init_stats")
-                .extract()
-                .expect("Malformed histogram definition");
+                .and_then(|h| h.extract::<Vec<(String, u64, u64, Option<u8>)>>())
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to get histograms from init_stats: {}", e);
+                    vec![]
+                });
 
-            for (name, min, max, accuracy) in histograms.iter().cloned() {
-                outputs.send(ScriptEvents::RegisterHistogram {
+            for (name, min, max, accuracy) in histograms {
+                if let Err(e) = Histogram::<u64>::new_with_bounds(min, max, accuracy.unwrap_or(3)) {
+                    eprintln!("Invalid histogram {} ({}-{}): {}", name, min, max, e);
+                    continue;
+                }
+                let _ = outputs.send(ScriptEvents::RegisterHistogram {
                     name,
                     min,
                     max,
@@ -100,20 +358,86 @@ fn launch_scripting_engine(
             }
         }
 
-        if let Ok(update) = module.getattr("handle_request") {
-            while let Ok(stats) = responses.recv() {
-                // Call script to update
-                if !stats.is_valid() {
-                    continue;
+        let update = module.getattr("handle_request").ok();
+        let handle_response = module.getattr("handle_response").ok();
+        let make_request = module.getattr("make_request").ok();
+        let init_requests = match module.getattr("init_requests") {
+            Ok(init_requests) => init_requests
+                .call0()
+                .and_then(|r| r.extract::<Vec<&PyAny>>())
+                .and_then(|r| r.into_iter().map(extract_override).collect())
+                .unwrap_or_else(|e| {
+                    println!("Failed to make requests in script: {}", e);
+                    vec![]
+                }),
+            Err(_) => vec![],
+        };
+        let mut requests = None;
+        let make_request_hook = make_request.map(|_| {
+            let (tx, rx) = flume::unbounded();
+            requests = Some(rx);
+            RequestHook(tx)
+        });
+        let _ = hook.send(ScriptHooks {
+            requests: init_requests.into_iter().flatten().collect(),
+            make_request: make_request_hook,
+            handle_response: handle_response.is_some(),
+        });
+        loop {
+            let input = match requests.as_ref() {
+                // Users are waiting on requests so make those before handling any responses
+                Some(requests) => match requests.try_recv() {
+                    Ok(call) => ScriptInput::Request(Ok(call)),
+                    Err(_) => Selector::new()
+                        .recv(&responses, ScriptInput::Response)
+                        .recv(requests, ScriptInput::Request)
+                        .wait(),
+                },
+                None => ScriptInput::Response(responses.recv()),
+            };
+            match input {
+                ScriptInput::Response(Ok(ScriptMessage::Flush)) => {
+                    let _ = outputs.send(ScriptEvents::Flushed);
+                }
+                ScriptInput::Response(Ok(ScriptMessage::Response(stats))) => {
+                    if let Some(handle_response) = handle_response {
+                        let res = Py::new(py, Response::from(&*stats))
+                            .and_then(|r| handle_response.call1((r,)));
+                        if let Err(e) = res {
+                            println!("Failed to send response to script: {}", e);
+                        }
+                    }
+                    // Call script to update
+                    let update = match update {
+                        Some(update) if stats.is_valid() => update,
+                        _ => continue,
+                    };
+                    let body_bytes = stats.body.unwrap_or_default();
+                    let body = body_bytes.as_ref().to_object(py);
+                    let status = stats.status.unwrap().as_u16().to_object(py);
+                    let time = (1000.0 * stats.request_time.unwrap().as_secs_f64()).to_object(py);
+                    let args =
+                        PyTuple::new(py, &[status, body, time, stats.connections.to_object(py)]);
+                    if let Err(e) = update.call1(args) {
+                        println!("Failed to send request to script: {}", e);
+                    }
                 }
-                let body_bytes = stats.body.unwrap_or_default();
-                let body = body_bytes.as_ref().to_object(py);
-                let status = stats.status.unwrap().as_u16().to_object(py);
-                let time = (1000.0 * stats.request_time.unwrap().as_secs_f64()).to_object(py);
-                let args = PyTuple::new(py, &[status, body, time, stats.connections.to_object(py)]);
-                if let Err(e) = update.call1(args) {
-                    println!("Failed to send request to script");
+                ScriptInput::Response(Err(_)) => break,
+                ScriptInput::Request(Ok(call)) => {
+                    let res = make_request
+                        .unwrap()
+                        .call1((call.user_id,))
+                        .and_then(extract_override);
+                    let res = match res {
+                        Ok(res) => res,
+                        Err(e) => {
+                            println!("Failed to make request in script: {}", e);
+                            None
+                        }
+                    };
+                    let _ = call.reply.send(res);
                 }
+                ScriptInput::Request(Err(_)) => requests = None,
             }
         }
 
@@ -6,10 +6,20 @@
 //! datum that can be sent to the method. This can be a value or an external_value where
 //! external_value is a path to a file or folder.
 //!
+//! Required parameters and JSON request bodies are also generated from the operation's parameters
+//! and requestBody, so an existing OpenAPI document can be used without any requestData.
+//!
+//! An operation or requestData entry can have an expect object with assertions to check on each
+//! response, such as the status, headers, body contents or latency.
+//!
+//! Paths, parameter values and constant bodies can contain template expressions such as
+//! `{{uuid}}` which are filled in for every request, see [`crate::template`]. Rows from the CSV or
+//! JSONL files in the feeders section can be used in them too.
+//!
 //! I'll also omit things that are in OpenAPI if I don't want to think about how to create the
 //! requests or if I have no use for them. They may get added later but who knows.
 use indexmap::IndexMap;
-use openapiv3::{Parameter, RequestBody};
+use openapiv3::{Components, Parameter, ReferenceOr, RequestBody};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -18,16 +28,140 @@ fn one() -> usize {
     1
 }
 
+#[doc(hidden)]
+fn default_chunk_ms() -> u64 {
+    100
+}
+
+#[doc(hidden)]
+fn get() -> String {
+    "GET".to_string()
+}
+
+#[doc(hidden)]
+fn default_poll_interval_ms() -> u64 {
+    500
+}
+
+#[doc(hidden)]
+fn default_max_polls() -> usize {
+    10
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Specification {
+    #[serde(default)]
     pub paths: IndexMap<String, PathItem>,
+    /// Schemas, parameters and request bodies that can be referenced from the operations
+    #[serde(default)]
+    pub components: Components,
+    /// Sequences of requests to run in order. If any are given users run these instead of
+    /// picking requests from the paths
+    #[serde(default)]
+    pub scenarios: IndexMap<String, Scenario>,
+    /// Files of test data. The columns of a row can be used in templates as `{{feeder.column}}`
+    #[serde(default)]
+    pub feeders: IndexMap<String, Feeder>,
+    /// Stages of a load profile to run instead of fixed levels of connections, written as on the
+    /// command line e.g. `0..200:60s`
+    #[serde(default)]
+    pub stages: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Feeder {
+    pub path: PathBuf,
+    /// Guessed from the file extension if not provided
+    pub format: Option<FeederFormat>,
+    #[serde(default)]
+    pub strategy: FeederStrategy,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FeederFormat {
+    /// Comma separated values with a header row naming the columns
+    Csv,
+    /// A JSON object on each line, the keys are the columns
+    Jsonl,
+}
+
+/// The order rows are used in, they're shared between all users
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FeederStrategy {
+    /// Use each row once in order, users stop when all the rows are used. Each level of a ramp
+    /// starts again from the first row
+    Sequential,
+    /// Pick a random row each time
+    Random,
+    /// Go through the rows in order starting again from the first at the end
+    #[default]
+    Circular,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+    #[serde(default = "one")]
+    pub weight: usize,
+}
+
+/// A request in a scenario. Variables extracted from earlier responses in the scenario can be
+/// used in the path, parameters and constant bodies with `{{name}}`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+    /// Name for the step in reports, defaults to the method and path
+    pub name: Option<String>,
+    #[serde(default = "get")]
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub parameters: Vec<TestParameter>,
+    /// If the body is external and a folder a random file from the folder is sent each time
+    pub body: Option<TestBody>,
+    pub expect: Option<Expect>,
+    /// Variables to take from the response for use in later steps
+    #[serde(default)]
+    pub extract: IndexMap<String, Extract>,
+    /// Repeat the request until the response passes these checks, for polling a job's status
+    pub until: Option<Expect>,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    /// Most times to send the request when polling before the scenario fails
+    #[serde(default = "default_max_polls")]
+    pub max_polls: usize,
+}
+
+/// Where to find the value of a variable in a response
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Extract {
+    /// JSON pointer into the body e.g. `/data/id`
+    Json(String),
+    Header(String),
+    /// Regex run on the body, the first capture group is used if there is one
+    Regex(String),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PathItem {
     pub get: Option<Operation>,
+    pub put: Option<Operation>,
     pub post: Option<Operation>,
+    pub delete: Option<Operation>,
+    pub options: Option<Operation>,
+    pub head: Option<Operation>,
+    pub patch: Option<Operation>,
+    pub trace: Option<Operation>,
+    /// Parameters shared by all the operations, an operation's own parameter with the same name
+    /// and location replaces one of these
+    #[serde(default)]
+    pub parameters: Vec<ReferenceOr<Parameter>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -36,18 +170,18 @@ pub struct Operation {
     #[serde(default)]
     pub request_data: IndexMap<String, Data>,
     #[serde(default)]
-    pub request_body: RequestBody,
+    pub request_body: Option<ReferenceOr<RequestBody>>,
     #[serde(default)]
-    pub parameters: Vec<Parameter>,
+    pub parameters: Vec<ReferenceOr<Parameter>>,
     #[serde(default = "one")]
     pub weight: usize,
+    /// Checks to make on the responses to every request for this operation
+    pub expect: Option<Expect>,
 }
 
 impl Operation {
     pub fn is_empty(&self) -> bool {
-        self.request_data.is_empty()
-            && self.request_body.content.is_empty()
-            && self.parameters.is_empty()
+        self.request_data.is_empty() && self.request_body.is_none() && self.parameters.is_empty()
     }
 }
 
@@ -59,6 +193,50 @@ pub struct Data {
     pub body: Option<TestBody>,
     #[serde(default = "one")]
     pub weight: usize,
+    /// Upload the body as a chunked stream at a fixed rate instead of all at once
+    pub pacing: Option<Pacing>,
+    /// Checks to make on the response, replacing any given for the operation
+    pub expect: Option<Expect>,
+}
+
+/// Assertions on a response. A response which fails any of them is counted as an assertion
+/// failure instead of a success
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Expect {
+    /// Allowed status codes, any 2xx status is allowed if this is empty
+    #[serde(default)]
+    pub status: Vec<u16>,
+    /// Headers which must be present, if a value is given the header must have that value
+    #[serde(default)]
+    pub headers: IndexMap<String, Option<String>>,
+    /// Text the body must contain
+    pub body_contains: Option<String>,
+    /// Regex the body must match
+    pub body_matches: Option<String>,
+    /// Values the JSON body must have, keyed by JSON pointer e.g. `/data/0/id`
+    #[serde(default)]
+    pub json: IndexMap<String, serde_json::Value>,
+    /// Longest the request can take in milliseconds
+    pub max_latency_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pacing {
+    pub rate: PacingRate,
+    /// How often a chunk of the body is sent in milliseconds
+    #[serde(default = "default_chunk_ms")]
+    pub chunk_ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PacingRate {
+    BytesPerSecond(usize),
+    /// Send WAV files as fast as they would play, taken from the sample rate, channels and sample
+    /// size in the header
+    RealTime,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -74,6 +252,36 @@ pub enum TestParameter {
 pub enum TestBody {
     Constant(String),
     External(PathBuf),
+    Multipart(MultipartBody),
+}
+
+/// A multipart/form-data body. A file can be a path to a folder in which case a request is made
+/// for every file in the folder. With several folders the files are paired up in turn rather than
+/// making every combination, so there's a request for each file in the largest folder and the
+/// smaller folders start again from their first file when they run out
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MultipartBody {
+    #[serde(default)]
+    pub fields: Vec<FormField>,
+    #[serde(default)]
+    pub files: Vec<FormFile>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormField {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormFile {
+    pub name: String,
+    pub path: PathBuf,
+    /// Guessed from the file extension if not provided
+    pub content_type: Option<String>,
 }
 
 #[cfg(test)]
@@ -97,6 +305,31 @@ mod tests {
         assert!(item.get.is_some());
     }
 
+    #[test]
+    fn all_methods() {
+        let sample_spec = r#"
+            paths:
+              resource:
+                put: {}
+                patch: {}
+                delete: {}
+                head: {}
+                options: {}
+                trace: {}
+        "#;
+
+        let spec: Specification = from_str(sample_spec).unwrap();
+        let item = spec.paths.get("resource").unwrap();
+        assert!(item.get.is_none());
+        assert!(item.post.is_none());
+        assert!(item.put.is_some());
+        assert!(item.patch.is_some());
+        assert!(item.delete.is_some());
+        assert!(item.head.is_some());
+        assert!(item.options.is_some());
+        assert!(item.trace.is_some());
+    }
+
     #[test]
     fn deserialise_specification() {
         let sample_spec = r#"
@@ -114,6 +347,19 @@ mod tests {
                     file_upload:
                       body:
                         external: "/home/xd009642/corpus"
+                      pacing:
+                        rate: realTime
+                        chunkMs: 50
+                    multipart_upload:
+                      body:
+                        multipart:
+                          fields:
+                            - name: language
+                              value: en
+                          files:
+                            - name: file
+                              path: "/home/xd009642/corpus"
+                              contentType: audio/wav
                   requestBody:
                     parameters:
                       - in: header
@@ -135,6 +381,18 @@ mod tests {
                           type: binary
         "#;
 
-        let _spec: Specification = from_str(sample_spec).unwrap();
+        let spec: Specification = from_str(sample_spec).unwrap();
+        let data = &spec.paths["upload"].post.as_ref().unwrap().request_data;
+        match data["multipart_upload"].body.as_ref() {
+            Some(TestBody::Multipart(m)) => {
+                assert_eq!(m.fields.len(), 1);
+                assert_eq!(m.files.len(), 1);
+                assert_eq!(m.files[0].content_type.as_deref(), Some("audio/wav"));
+            }
+            b => panic!("Expected multipart body, got {:?}", b),
+        }
+        let pacing = data["file_upload"].pacing.as_ref().unwrap();
+        assert!(matches!(pacing.rate, PacingRate::RealTime));
+        assert_eq!(pacing.chunk_ms, 50);
     }
 }
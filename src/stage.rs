@@ -0,0 +1,143 @@
+//! Stages make up a load profile where the number of users changes over time, e.g. ramping up from
+//! 0 to 200 users over a minute, holding at 200 for five minutes and ramping back down to 0. A stage
+//! is written `from..to:duration`, or `to:duration` to start from the number of users at the end of
+//! the previous stage (0 for the first). A duration without units is in seconds.
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Stage {
+    /// Users at the start of the stage, defaults to the target of the previous stage
+    pub from: Option<usize>,
+    /// Users at the end of the stage
+    pub target: usize,
+    pub duration: Duration,
+}
+
+impl FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (users, duration) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Stage {} should be `[from..]to:duration`", s))?;
+        let parse_users = |u: &str| {
+            u.trim()
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid number of users {}: {}", u, e))
+        };
+        let (from, target) = match users.split_once("..") {
+            Some((from, to)) => (Some(parse_users(from)?), parse_users(to)?),
+            None => (None, parse_users(users)?),
+        };
+        let duration = duration.trim();
+        let duration = match duration.parse::<u64>() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(_) => humantime::parse_duration(duration)
+                .map_err(|e| format!("Invalid duration {}: {}", duration, e))?,
+        };
+        Ok(Self {
+            from,
+            target,
+            duration,
+        })
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let duration = humantime::format_duration(self.duration);
+        match self.from {
+            Some(from) if from != self.target => write!(
+                f,
+                "ramp from {} to {} users over {}",
+                from, self.target, duration
+            ),
+            _ => write!(f, "{} users for {}", self.target, duration),
+        }
+    }
+}
+
+impl Stage {
+    /// Fills in the starting number of users from the previous stage where it isn't given
+    pub fn resolve(stages: &[Stage]) -> Vec<Stage> {
+        let mut users = 0;
+        stages
+            .iter()
+            .map(|stage| {
+                let stage = Stage {
+                    from: Some(stage.from.unwrap_or(users)),
+                    ..*stage
+                };
+                users = stage.target;
+                stage
+            })
+            .collect()
+    }
+
+    /// Most users running at once during the stage
+    pub fn peak(&self) -> usize {
+        self.from.unwrap_or_default().max(self.target)
+    }
+
+    /// Number of users there should be after the given time in the stage, moving linearly from
+    /// the start to the target
+    pub fn users_at(&self, elapsed: Duration) -> usize {
+        let from = self.from.unwrap_or_default() as f64;
+        let progress = if self.duration.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+        };
+        (from + (self.target as f64 - from) * progress).round() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_interpolate() {
+        let stages = ["0..200:60s", "200:5m", "10", "0:30"]
+            .iter()
+            .map(|s| s.parse::<Stage>())
+            .collect::<Vec<_>>();
+        assert!(stages[2].is_err());
+        let stages = stages
+            .into_iter()
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+        let stages = Stage::resolve(&stages);
+        assert_eq!(
+            stages,
+            vec![
+                Stage {
+                    from: Some(0),
+                    target: 200,
+                    duration: Duration::from_secs(60)
+                },
+                Stage {
+                    from: Some(200),
+                    target: 200,
+                    duration: Duration::from_secs(300)
+                },
+                Stage {
+                    from: Some(200),
+                    target: 0,
+                    duration: Duration::from_secs(30)
+                },
+            ]
+        );
+        assert_eq!(stages[0].to_string(), "ramp from 0 to 200 users over 1m");
+        assert_eq!(stages[1].to_string(), "200 users for 5m");
+
+        assert_eq!(stages[0].users_at(Duration::from_secs(0)), 0);
+        assert_eq!(stages[0].users_at(Duration::from_secs(15)), 50);
+        assert_eq!(stages[0].users_at(Duration::from_secs(90)), 200);
+        assert_eq!(stages[1].users_at(Duration::from_secs(100)), 200);
+        assert_eq!(stages[2].users_at(Duration::from_secs(15)), 100);
+        assert_eq!(stages[2].peak(), 200);
+    }
+}
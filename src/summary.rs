@@ -1,36 +1,354 @@
+use crate::request::RequestInfo;
 use bytes::Bytes;
 use hdrhistogram::Histogram;
-use hyper::StatusCode;
+use hyper::{HeaderMap, StatusCode};
+use serde::ser::{SerializeMap, Serializer};
+use serde::Serialize;
 use std::collections::BTreeMap;
+use std::error::Error;
 use std::fmt;
-use std::time::Duration;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-#[derive(Clone, Debug, PartialEq, Eq, Ord, PartialOrd)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct RequestStats {
+    /// When the request was sent
+    pub start_time: Option<SystemTime>,
     pub request_time: Option<Duration>,
+    /// Time between when the request was meant to be sent and when it was actually sent. Only
+    /// present when running at a constant rate
+    pub wait_time: Option<Duration>,
+    /// Time from the last chunk of a paced upload being sent to the response completing
+    pub post_upload_time: Option<Duration>,
     pub status: Option<StatusCode>,
+    pub headers: Option<HeaderMap>,
     pub bytes_read: Option<usize>,
     pub bytes_written: Option<usize>,
     pub body: Option<Bytes>,
     pub timeout: bool,
+    /// Why the request failed if it failed before a full response was received
+    pub error: Option<RequestError>,
+    /// Names of the assertions the response failed, only present if the request had assertions
+    pub assertion_failures: Option<Vec<String>>,
+    /// Time spent in each phase of the request, phases which didn't happen are missing
+    pub phases: BTreeMap<Phase, Duration>,
+    /// The TLS handshake done if a new TLS connection was opened for this request
+    pub tls_handshake: Option<TlsHandshake>,
+    /// The request which was sent
+    pub request: Option<Arc<RequestInfo>>,
+    /// Set on the last request of a scenario, whether it completed or failed at this step
+    pub scenario: Option<ScenarioResult>,
+    /// ID of the connection the response came back on
+    pub connection_id: Option<usize>,
+    /// Whether the connection was opened for this request rather than reused
+    pub new_connection: Option<bool>,
+    /// Requests in flight on the same connection when the response arrived, including this one
+    pub concurrent_streams: Option<usize>,
     pub connections: usize,
+    /// Index of the stage the request finished in when running stages
+    pub stage: Option<usize>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Waiting for a connection to become available
+    PoolWait,
+    /// Opening a new connection
+    Connect,
+    /// TLS handshake on a new connection
+    Tls,
+    /// Time from sending the request to getting the response headers
+    FirstByte,
+    /// Reading the response body
+    Download,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScenarioResult {
+    pub name: Arc<str>,
+    /// Time from the start of the first step to the end of this one
+    pub time: Duration,
+    /// False if the scenario stopped early because this step failed
+    pub completed: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ScenarioCounts {
+    pub completed: usize,
+    pub failed: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsHandshake {
+    Full,
+    /// An earlier session was resumed
+    Resumed,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::PoolWait => "pool wait",
+            Self::Connect => "connect",
+            Self::Tls => "TLS handshake",
+            Self::FirstByte => "time to first byte",
+            Self::Download => "body download",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestError {
+    /// Failed to resolve the hostname
+    Dns,
+    /// The server refused the connection
+    ConnectionRefused,
+    /// Any other failure to establish a connection
+    Connect,
+    /// The connection was reset or closed before the response was complete
+    ConnectionReset,
+    /// The response headers were received but reading the body failed
+    BodyRead,
+    /// The server sent something that isn't valid HTTP
+    Protocol,
+    Other,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Dns => "dns",
+            Self::ConnectionRefused => "connection refused",
+            Self::Connect => "connect",
+            Self::ConnectionReset => "connection reset",
+            Self::BodyRead => "body read",
+            Self::Protocol => "protocol",
+            Self::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+fn io_error_kind(err: &(dyn Error + 'static)) -> Option<io::ErrorKind> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<io::Error>() {
+            return Some(io.kind());
+        }
+        source = err.source();
+    }
+    None
+}
+
+impl From<&hyper::Error> for RequestError {
+    fn from(err: &hyper::Error) -> Self {
+        let kind = io_error_kind(err);
+        if err.is_connect() {
+            // hyper doesn't expose its connect error type so the message is all we have to go on
+            let mut source = err.source();
+            while let Some(e) = source {
+                if e.to_string().starts_with("dns error") {
+                    return Self::Dns;
+                }
+                source = e.source();
+            }
+            match kind {
+                Some(io::ErrorKind::ConnectionRefused) => Self::ConnectionRefused,
+                _ => Self::Connect,
+            }
+        } else if err.is_parse() || err.is_parse_status() || err.is_parse_too_large() {
+            Self::Protocol
+        } else if err.is_incomplete_message()
+            || matches!(
+                kind,
+                Some(io::ErrorKind::ConnectionReset)
+                    | Some(io::ErrorKind::ConnectionAborted)
+                    | Some(io::ErrorKind::BrokenPipe)
+            )
+        {
+            Self::ConnectionReset
+        } else {
+            Self::Other
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Summary {
     pub success: usize,
     pub failure: usize,
     pub timeout: usize,
+    /// Failed requests which didn't get a full response broken down by the reason
+    pub errors: BTreeMap<RequestError, usize>,
+    /// Responses which failed one or more assertions, these aren't included in the successes or
+    /// failures
+    pub assertion_failed: usize,
+    /// Number of times each assertion failed
+    pub assertion_failures: BTreeMap<String, usize>,
+    pub tls_handshakes: usize,
+    /// How many of the TLS handshakes resumed a previous session
+    pub tls_resumptions: usize,
+    pub connections_opened: usize,
+    /// Requests sent on a connection which was already open
+    pub connections_reused: usize,
+    /// Idle connections closed by the server rather than by murk, such as by a keep-alive
+    /// timeout. Filled in at the end of a level
+    pub connections_closed_by_server: usize,
+    /// Number of responses received on each connection, keyed by connection ID. For HTTP/2 this is
+    /// the number of streams used on the connection
+    pub streams_per_connection: BTreeMap<usize, usize>,
+    /// Most requests seen in flight on a single connection at once
+    pub max_concurrent_streams: usize,
     pub bytes_read: usize,
     pub bytes_written: usize,
     pub status_codes: BTreeMap<u16, usize>,
+    /// How many times each request was sent, whether or not it succeeded
+    pub requests_sent: BTreeMap<String, usize>,
+    /// Request latencies in milliseconds, as are the other latency histograms apart from the
+    /// phases. The exported keys say the units
+    #[serde(rename = "histogram_ms", serialize_with = "serialize_histogram")]
     pub histogram: Histogram<u64>,
+    /// Whether to keep a latency histogram for each status code
+    #[serde(skip)]
+    pub track_status_latency: bool,
+    #[serde(
+        rename = "status_histograms_ms",
+        serialize_with = "serialize_histograms"
+    )]
+    pub status_histograms: BTreeMap<u16, Histogram<u64>>,
+    /// Time spent in each phase of the requests in microseconds, as connecting can take well
+    /// under a millisecond
+    #[serde(
+        rename = "phase_histograms_us",
+        serialize_with = "serialize_histograms"
+    )]
+    pub phase_histograms: BTreeMap<Phase, Histogram<u64>>,
+    /// Latencies measured from the intended start time of each request, this corrects for
+    /// coordinated omission and is only populated when running at a constant rate
+    #[serde(
+        rename = "corrected_histogram_ms",
+        serialize_with = "serialize_histogram"
+    )]
+    pub corrected_histogram: Histogram<u64>,
+    /// Time from the end of a paced upload to the response completing, only populated for paced
+    /// uploads
+    #[serde(
+        rename = "post_upload_histogram_ms",
+        serialize_with = "serialize_histogram"
+    )]
+    pub post_upload_histogram: Histogram<u64>,
+    #[serde(serialize_with = "serialize_histograms")]
     pub custom_histograms: BTreeMap<String, Histogram<u64>>,
+    /// How many times each scenario was completed or stopped by a failing step
+    pub scenarios: BTreeMap<String, ScenarioCounts>,
+    /// Latencies of completed scenarios from the start of the first step to the end of the last
+    #[serde(
+        rename = "scenario_histograms_ms",
+        serialize_with = "serialize_histograms"
+    )]
+    pub scenario_histograms: BTreeMap<String, Histogram<u64>>,
+    /// Latencies of each scenario step
+    #[serde(rename = "step_histograms_ms", serialize_with = "serialize_histograms")]
+    pub step_histograms: BTreeMap<String, Histogram<u64>>,
+}
+
+/// Results of testing at one level of concurrent connections
+#[derive(Clone, Debug, Serialize)]
+pub struct LevelSummary {
+    pub connections: usize,
+    /// Description of the stage when running stages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    /// When testing at this level started
+    #[serde(skip)]
+    pub start: SystemTime,
+    /// How long the level ran for
+    #[serde(skip)]
+    pub duration: Duration,
+    #[serde(flatten)]
+    pub summary: Summary,
+    /// The results split into slices of time for the HdrHistogram interval log
+    #[serde(skip)]
+    pub intervals: Vec<IntervalSummary>,
+}
+
+/// Results for one slice of time while testing
+#[derive(Clone, Debug)]
+pub struct IntervalSummary {
+    pub start: SystemTime,
+    pub duration: Duration,
+    /// Index of the stage the requests finished in when running stages
+    pub stage: Option<usize>,
+    pub summary: Summary,
+}
+
+const QUANTILES: [f64; 6] = [0.5, 0.75, 0.9, 0.95, 0.99, 0.999];
+
+/// Exported form of a histogram, as the raw histogram isn't much use outside of murk
+#[derive(Serialize)]
+struct HistogramSummary {
+    count: u64,
+    min: u64,
+    max: u64,
+    mean: f64,
+    stdev: f64,
+    quantiles: BTreeMap<String, u64>,
+}
+
+impl From<&Histogram<u64>> for HistogramSummary {
+    fn from(hist: &Histogram<u64>) -> Self {
+        let quantiles = QUANTILES
+            .iter()
+            .map(|q| (format!("p{}", q * 100.0), hist.value_at_quantile(*q)))
+            .collect();
+        Self {
+            count: hist.len(),
+            min: hist.min(),
+            max: hist.max(),
+            mean: hist.mean(),
+            stdev: hist.stdev(),
+            quantiles,
+        }
+    }
+}
+
+fn serialize_histogram<S: Serializer>(hist: &Histogram<u64>, s: S) -> Result<S::Ok, S::Error> {
+    HistogramSummary::from(hist).serialize(s)
+}
+
+fn serialize_histograms<K: Serialize, S: Serializer>(
+    hists: &BTreeMap<K, Histogram<u64>>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    let mut map = s.serialize_map(Some(hists.len()))?;
+    for (name, hist) in hists {
+        map.serialize_entry(name, &HistogramSummary::from(hist))?;
+    }
+    map.end()
 }
 
 impl RequestStats {
+    /// Whether a full response was received and it passed any assertions or had a 2xx status if
+    /// there weren't any
+    pub fn succeeded(&self) -> bool {
+        if self.timeout || self.error.is_some() {
+            return false;
+        }
+        match (self.status, self.assertion_failures.as_ref()) {
+            (Some(_), Some(failures)) => failures.is_empty(),
+            (Some(status), None) => status.is_success(),
+            (None, _) => false,
+        }
+    }
+
     pub fn is_valid(&self) -> bool {
-        self.request_time.is_some()
+        self.error.is_none()
+            && self.request_time.is_some()
             && self.status.is_some()
             && self.bytes_read.is_some()
             && self.bytes_written.is_some()
@@ -42,19 +360,96 @@ impl Summary {
         Self {
             // This should maybe have a buffer
             histogram: Histogram::<u64>::new_with_max(timeout.as_millis() as u64, 3).unwrap(),
+            // Time spent waiting to send isn't bounded by the timeout so let this one grow
+            corrected_histogram: Histogram::<u64>::new(3).unwrap(),
+            post_upload_histogram: Histogram::<u64>::new_with_max(timeout.as_millis() as u64, 3)
+                .unwrap(),
             success: 0,
             failure: 0,
             timeout: 0,
+            errors: BTreeMap::new(),
+            assertion_failed: 0,
+            assertion_failures: BTreeMap::new(),
+            tls_handshakes: 0,
+            tls_resumptions: 0,
+            connections_opened: 0,
+            connections_reused: 0,
+            connections_closed_by_server: 0,
+            streams_per_connection: BTreeMap::new(),
+            max_concurrent_streams: 0,
             bytes_read: 0,
             bytes_written: 0,
             custom_histograms: BTreeMap::new(),
             status_codes: BTreeMap::new(),
+            requests_sent: BTreeMap::new(),
+            track_status_latency: false,
+            status_histograms: BTreeMap::new(),
+            phase_histograms: BTreeMap::new(),
+            scenarios: BTreeMap::new(),
+            scenario_histograms: BTreeMap::new(),
+            step_histograms: BTreeMap::new(),
+        }
+    }
+
+    /// Creates an empty summary keeping the same histogram configurations including any
+    /// registered custom histograms
+    pub fn cleared(&self) -> Self {
+        let mut res = self.clone();
+        res.success = 0;
+        res.failure = 0;
+        res.timeout = 0;
+        res.errors.clear();
+        res.assertion_failed = 0;
+        res.assertion_failures.clear();
+        res.tls_handshakes = 0;
+        res.tls_resumptions = 0;
+        res.connections_opened = 0;
+        res.connections_reused = 0;
+        res.connections_closed_by_server = 0;
+        res.streams_per_connection.clear();
+        res.max_concurrent_streams = 0;
+        res.bytes_read = 0;
+        res.bytes_written = 0;
+        res.status_codes.clear();
+        res.requests_sent.clear();
+        res.status_histograms.clear();
+        res.phase_histograms.clear();
+        res.scenarios.clear();
+        res.scenario_histograms.clear();
+        res.step_histograms.clear();
+        res.histogram.reset();
+        res.corrected_histogram.reset();
+        res.post_upload_histogram.reset();
+        for hist in res.custom_histograms.values_mut() {
+            hist.reset();
         }
+        res
     }
 
-    pub fn register_custom_histogram(&mut self, name: String, min: u64, max: u64, accuracy: u8) {
-        let hist = Histogram::<u64>::new_with_bounds(min, max, accuracy).unwrap();
+    /// Adds a histogram for a script to record into, failing if the bounds or accuracy aren't ones
+    /// a histogram can have
+    pub fn register_custom_histogram(
+        &mut self,
+        name: String,
+        min: u64,
+        max: u64,
+        accuracy: u8,
+    ) -> Result<(), String> {
+        let hist = Histogram::<u64>::new_with_bounds(min, max, accuracy)
+            .map_err(|e| format!("Invalid histogram {}: {}", name, e))?;
         self.custom_histograms.insert(name, hist);
+        Ok(())
+    }
+
+    /// Records a value into a custom histogram. Returns false if no histogram was registered under
+    /// that name
+    pub fn record_custom(&mut self, name: &str, value: u64) -> bool {
+        if let Some(hist) = self.custom_histograms.get_mut(name) {
+            let _ = hist.record(value);
+            true
+        } else {
+            false
+        }
     }
 }
 
@@ -63,40 +458,178 @@ impl fmt::Display for Summary {
         writeln!(f, "Successful requests: {}", self.success)?;
         writeln!(f, "Failed requests: {}", self.failure)?;
         writeln!(f, "Timed out requests: {}", self.timeout)?;
+        if !self.errors.is_empty() {
+            let errors = self
+                .errors
+                .iter()
+                .map(|(err, count)| format!("{}: {}", err, count))
+                .collect::<Vec<_>>();
+            writeln!(f, "Request errors: {}", errors.join(", "))?;
+        }
+        if self.assertion_failed > 0 {
+            writeln!(f, "Failed assertions: {}", self.assertion_failed)?;
+            let failures = self
+                .assertion_failures
+                .iter()
+                .map(|(name, count)| format!("{}: {}", name, count))
+                .collect::<Vec<_>>();
+            writeln!(f, "Assertion failures: {}", failures.join(", "))?;
+        }
+        if self.tls_handshakes > 0 {
+            writeln!(
+                f,
+                "TLS handshakes: {} ({} resumed)",
+                self.tls_handshakes, self.tls_resumptions
+            )?;
+        }
+        writeln!(
+            f,
+            "Connections opened: {}, reused: {}, closed by server: {}",
+            self.connections_opened, self.connections_reused, self.connections_closed_by_server
+        )?;
+        if let (Some(min), Some(max)) = (
+            self.streams_per_connection.values().min(),
+            self.streams_per_connection.values().max(),
+        ) {
+            let total = self.streams_per_connection.values().sum::<usize>();
+            writeln!(
+                f,
+                "Connections used: {}, streams per connection min/mean/max: {}/{:.1}/{}, max concurrent streams: {}",
+                self.streams_per_connection.len(),
+                min,
+                total as f64 / self.streams_per_connection.len() as f64,
+                max,
+                self.max_concurrent_streams
+            )?;
+        }
         writeln!(f, "Bytes read: {}", self.bytes_read)?;
         writeln!(f, "Bytes written: {}", self.bytes_written)?;
+        let codes = self
+            .status_codes
+            .iter()
+            .map(|(code, count)| format!("{}: {}", code, count))
+            .collect::<Vec<_>>();
+        writeln!(f, "Status codes: {}", codes.join(", "))?;
+        if self.requests_sent.len() > 1 {
+            writeln!(f, "\nRequests sent:")?;
+            for (name, count) in &self.requests_sent {
+                writeln!(f, "{}: {}", name, count)?;
+            }
+        }
         writeln!(f, "\nQuantile durations:")?;
-        let quantiles = [0.5, 0.75, 0.9, 0.95, 0.99, 0.999];
-        for quant in &quantiles {
+        write_quantiles(f, &self.histogram)?;
+        if !self.corrected_histogram.is_empty() {
+            writeln!(f, "\nCorrected quantile durations:")?;
+            write_quantiles(f, &self.corrected_histogram)?;
+        }
+        if !self.post_upload_histogram.is_empty() {
+            writeln!(f, "\nQuantile durations after upload finished:")?;
+            write_quantiles(f, &self.post_upload_histogram)?;
+        }
+        for (phase, hist) in &self.phase_histograms {
+            writeln!(f, "\nQuantile durations for {} (us):", phase)?;
+            write_quantiles(f, hist)?;
+        }
+        for (code, hist) in &self.status_histograms {
+            writeln!(f, "\nQuantile durations for status {}:", code)?;
+            write_quantiles(f, hist)?;
+        }
+        for (name, counts) in &self.scenarios {
             writeln!(
                 f,
-                "{}'th percentile: {}",
-                *quant * 100.0,
-                self.histogram.value_at_quantile(*quant)
+                "\nScenario {}: {} completed, {} failed",
+                name, counts.completed, counts.failed
             )?;
+            if let Some(hist) = self.scenario_histograms.get(name) {
+                write_quantiles(f, hist)?;
+            }
+        }
+        for (name, hist) in &self.step_histograms {
+            writeln!(f, "\nQuantile durations for step {}:", name)?;
+            write_quantiles(f, hist)?;
+        }
+        for (name, hist) in &self.custom_histograms {
+            writeln!(f, "\nQuantiles for {}:", name)?;
+            write_quantiles(f, hist)?;
         }
         Ok(())
     }
 }
 
+fn write_quantiles(f: &mut fmt::Formatter<'_>, histogram: &Histogram<u64>) -> fmt::Result {
+    for quant in &QUANTILES {
+        writeln!(
+            f,
+            "{}'th percentile: {}",
+            *quant * 100.0,
+            histogram.value_at_quantile(*quant)
+        )?;
+    }
+    Ok(())
+}
+
+fn merge_histograms<K: Ord>(
+    into: &mut BTreeMap<K, Histogram<u64>>,
+    from: BTreeMap<K, Histogram<u64>>,
+) {
+    for (k, v) in from {
+        match into.get_mut(&k) {
+            Some(hist) => hist.add(v).unwrap(),
+            None => {
+                into.insert(k, v);
+            }
+        }
+    }
+}
+
 impl std::ops::AddAssign for Summary {
     fn add_assign(&mut self, other: Self) {
         self.success += other.success;
         self.failure += other.failure;
         self.timeout += other.timeout;
+        for (k, v) in other.errors {
+            *self.errors.entry(k).or_default() += v;
+        }
+        self.assertion_failed += other.assertion_failed;
+        for (k, v) in other.assertion_failures {
+            *self.assertion_failures.entry(k).or_default() += v;
+        }
+        self.tls_handshakes += other.tls_handshakes;
+        self.tls_resumptions += other.tls_resumptions;
+        self.connections_opened += other.connections_opened;
+        self.connections_reused += other.connections_reused;
+        self.connections_closed_by_server += other.connections_closed_by_server;
+        for (k, v) in other.streams_per_connection {
+            *self.streams_per_connection.entry(k).or_default() += v;
+        }
+        self.max_concurrent_streams = self
+            .max_concurrent_streams
+            .max(other.max_concurrent_streams);
         self.bytes_read += other.bytes_read;
         self.bytes_written += other.bytes_written;
         self.histogram.add(other.histogram).unwrap();
-        for (k, v) in self.status_codes.iter_mut() {
-            if let Some(v2) = other.status_codes.get(k) {
-                *v += v2;
-            }
+        self.corrected_histogram
+            .add(other.corrected_histogram)
+            .unwrap();
+        self.post_upload_histogram
+            .add(other.post_upload_histogram)
+            .unwrap();
+        for (k, v) in other.status_codes {
+            *self.status_codes.entry(k).or_default() += v;
         }
-        for (k, v) in self.custom_histograms.iter_mut() {
-            if let Some(v2) = other.custom_histograms.get(k) {
-                v.add(v2).unwrap();
-            }
+        for (k, v) in other.requests_sent {
+            *self.requests_sent.entry(k).or_default() += v;
+        }
+        merge_histograms(&mut self.status_histograms, other.status_histograms);
+        merge_histograms(&mut self.phase_histograms, other.phase_histograms);
+        merge_histograms(&mut self.custom_histograms, other.custom_histograms);
+        for (k, v) in other.scenarios {
+            let counts = self.scenarios.entry(k).or_default();
+            counts.completed += v.completed;
+            counts.failed += v.failed;
         }
+        merge_histograms(&mut self.scenario_histograms, other.scenario_histograms);
+        merge_histograms(&mut self.step_histograms, other.step_histograms);
     }
 }
 
@@ -104,13 +637,101 @@ impl std::ops::AddAssign<RequestStats> for Summary {
     fn add_assign(&mut self, stat: RequestStats) {
         self.bytes_read += stat.bytes_read.unwrap_or_default();
         self.bytes_written += stat.bytes_written.unwrap_or_default();
+        if let Some(handshake) = stat.tls_handshake {
+            self.tls_handshakes += 1;
+            self.tls_resumptions += (handshake == TlsHandshake::Resumed) as usize;
+        }
+        if let Some(name) = stat.request.as_ref().map(|r| &r.name) {
+            match self.requests_sent.get_mut(&**name) {
+                Some(count) => *count += 1,
+                None => {
+                    self.requests_sent.insert(name.to_string(), 1);
+                }
+            }
+        }
+        if let Some(scenario) = stat.scenario.as_ref() {
+            let counts = self.scenarios.entry(scenario.name.to_string()).or_default();
+            if scenario.completed {
+                counts.completed += 1;
+                let histogram = &self.corrected_histogram;
+                let _ = self
+                    .scenario_histograms
+                    .entry(scenario.name.to_string())
+                    .or_insert_with(|| Histogram::new_from(histogram))
+                    .record(scenario.time.as_millis() as u64);
+            } else {
+                counts.failed += 1;
+            }
+        }
+        match stat.new_connection {
+            Some(true) => self.connections_opened += 1,
+            Some(false) => self.connections_reused += 1,
+            None => {}
+        }
+        if let Some(id) = stat.connection_id {
+            *self.streams_per_connection.entry(id).or_default() += 1;
+        }
+        if let Some(concurrent) = stat.concurrent_streams {
+            self.max_concurrent_streams = self.max_concurrent_streams.max(concurrent);
+        }
+        // A response can fail after its status arrives, such as when the body is cut off
+        if let Some(code) = stat.status {
+            *self.status_codes.entry(code.as_u16()).or_default() += 1;
+        }
         if stat.timeout {
             self.timeout += 1;
+        } else if let Some(err) = stat.error {
+            self.failure += 1;
+            *self.errors.entry(err).or_default() += 1;
         } else if let Some(code) = stat.status {
-            self.success += code.is_success() as usize;
-            self.failure += !code.is_success() as usize;
-            if let Some(time) = stat.request_time.map(|x| x.as_millis() as u64) {
-                self.histogram.record(time);
+            match stat.assertion_failures.as_ref() {
+                Some(failures) if !failures.is_empty() => {
+                    self.assertion_failed += 1;
+                    for name in failures {
+                        *self.assertion_failures.entry(name.clone()).or_default() += 1;
+                    }
+                }
+                // The assertions decide which statuses are successful
+                Some(_) => self.success += 1,
+                None => {
+                    self.success += code.is_success() as usize;
+                    self.failure += !code.is_success() as usize;
+                }
+            }
+            if let Some(time) = stat.request_time {
+                let _ = self.histogram.record(time.as_millis() as u64);
+                if let Some(wait) = stat.wait_time {
+                    let _ = self
+                        .corrected_histogram
+                        .record((time + wait).as_millis() as u64);
+                }
+                if let Some(upload) = stat.post_upload_time {
+                    let _ = self.post_upload_histogram.record(upload.as_millis() as u64);
+                }
+                for (phase, time) in &stat.phases {
+                    // Downloading the body isn't bounded by the timeout so these grow as needed
+                    let _ = self
+                        .phase_histograms
+                        .entry(*phase)
+                        .or_insert_with(|| Histogram::new(3).unwrap())
+                        .record(time.as_micros() as u64);
+                }
+                if let Some(request) = stat.request.as_ref().filter(|r| r.scenario.is_some()) {
+                    let histogram = &self.histogram;
+                    let _ = self
+                        .step_histograms
+                        .entry(request.name.to_string())
+                        .or_insert_with(|| Histogram::new_from(histogram))
+                        .record(time.as_millis() as u64);
+                }
+                if self.track_status_latency {
+                    let histogram = &self.histogram;
+                    let _ = self
+                        .status_histograms
+                        .entry(code.as_u16())
+                        .or_insert_with(|| Histogram::new_from(histogram))
+                        .record(time.as_millis() as u64);
+                }
             }
         }
     }
@@ -120,27 +741,112 @@ impl std::ops::Add for Summary {
     type Output = Self;
 
     fn add(mut self, other: Self) -> Self {
-        let mut histogram = self.histogram.clone();
-        histogram.add(other.histogram).unwrap();
-        for (k, v) in self.status_codes.iter_mut() {
-            if let Some(v2) = other.status_codes.get(k) {
-                *v += v2;
-            }
-        }
-        for (k, v) in self.custom_histograms.iter_mut() {
-            if let Some(v2) = other.custom_histograms.get(k) {
-                v.add(v2).unwrap();
-            }
-        }
-        Self {
-            histogram,
-            success: self.success + other.success,
-            failure: self.failure + other.failure,
-            timeout: self.timeout + other.timeout,
-            bytes_read: self.bytes_read + other.bytes_read,
-            bytes_written: self.bytes_written + other.bytes_written,
-            status_codes: self.status_codes,
-            custom_histograms: self.custom_histograms,
+        self += other;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(status: u16, millis: u64) -> RequestStats {
+        RequestStats {
+            request_time: Some(Duration::from_millis(millis)),
+            status: Some(StatusCode::from_u16(status).unwrap()),
+            bytes_read: Some(0),
+            bytes_written: Some(0),
+            connections: 1,
+            ..Default::default()
         }
     }
+
+    #[test]
+    fn status_codes_recorded() {
+        let mut summary = Summary::new(Duration::from_secs(1));
+        summary += stat(200, 10);
+        summary += stat(200, 12);
+        summary += stat(503, 1);
+
+        assert_eq!(summary.success, 2);
+        assert_eq!(summary.failure, 1);
+        assert_eq!(summary.status_codes.get(&200), Some(&2));
+        assert_eq!(summary.status_codes.get(&503), Some(&1));
+        assert!(summary.status_histograms.is_empty());
+    }
+
+    #[test]
+    fn merge_is_union() {
+        let mut a = Summary::new(Duration::from_secs(1));
+        a.track_status_latency = true;
+        let mut b = a.clone();
+        a += stat(200, 10);
+        b += stat(200, 20);
+        b += stat(404, 5);
+
+        let merged = a + b;
+        assert_eq!(merged.status_codes.get(&200), Some(&2));
+        assert_eq!(merged.status_codes.get(&404), Some(&1));
+        assert_eq!(merged.status_histograms[&200].len(), 2);
+        assert_eq!(merged.status_histograms[&404].len(), 1);
+        assert_eq!(merged.histogram.len(), 3);
+    }
+
+    #[test]
+    fn phases_in_microseconds() {
+        let mut summary = Summary::new(Duration::from_secs(1));
+        let mut fast = stat(200, 1);
+        fast.phases
+            .insert(Phase::Connect, Duration::from_micros(250));
+        fast.phases.insert(Phase::Download, Duration::from_secs(30));
+        summary += fast;
+
+        assert_eq!(summary.phase_histograms[&Phase::Connect].max(), 250);
+        assert!(summary.phase_histograms[&Phase::Download].max() >= 30_000_000);
+    }
+
+    #[test]
+    fn errors_are_failures() {
+        let mut summary = Summary::new(Duration::from_secs(1));
+        let mut body_err = stat(200, 10);
+        body_err.error = Some(RequestError::BodyRead);
+        summary += body_err;
+        summary += RequestStats {
+            request_time: None,
+            status: None,
+            bytes_read: None,
+            body: None,
+            error: Some(RequestError::ConnectionRefused),
+            ..stat(200, 0)
+        };
+
+        assert_eq!(summary.success, 0);
+        assert_eq!(summary.failure, 2);
+        assert_eq!(summary.errors.get(&RequestError::BodyRead), Some(&1));
+        // The status of the response with a broken body still counts
+        assert_eq!(summary.status_codes.get(&200), Some(&1));
+        assert_eq!(
+            summary.errors.get(&RequestError::ConnectionRefused),
+            Some(&1)
+        );
+    }
+
+    #[test]
+    fn assertion_failures_counted_separately() {
+        let mut summary = Summary::new(Duration::from_secs(1));
+        summary += RequestStats {
+            assertion_failures: Some(vec!["body contains".to_string()]),
+            ..stat(200, 10)
+        };
+        summary += RequestStats {
+            assertion_failures: Some(vec![]),
+            ..stat(404, 10)
+        };
+
+        assert_eq!(summary.success, 1);
+        assert_eq!(summary.failure, 0);
+        assert_eq!(summary.assertion_failed, 1);
+        assert_eq!(summary.assertion_failures.get("body contains"), Some(&1));
+        assert_eq!(summary.status_codes.get(&200), Some(&1));
+    }
 }
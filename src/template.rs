@@ -0,0 +1,340 @@
+//! Template expressions can be used in the paths, parameters and constant bodies in the
+//! specification. They're evaluated for every request so each one can be different, for example
+//! to give every request a unique ID for tracing. The expressions are:
+//!
+//! * `{{uuid}}` a random v4 UUID
+//! * `{{counter}}` a number which goes up by one for every request made from a template
+//! * `{{random_int 1 100}}` a random integer between the bounds, inclusive
+//! * `{{timestamp}}` the current Unix time in seconds
+//! * `{{env "TOKEN"}}` an environment variable, read once when the specification is loaded
+//! * `{{file_name}}` the name of the file the body is read from, empty if there isn't one
+//! * `{{name}}` a variable, such as one extracted from a response in an earlier scenario step
+//!
+//! Any `{{` in these strings starts an expression, write `\{{` for a literal `{{`.
+use rand::Rng;
+use std::collections::HashMap;
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+use uuid::Uuid;
+
+/// Values for the variables in templates
+pub type Variables = HashMap<String, String>;
+
+/// Shared by all users so every request gets a different value for `{{counter}}`
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Variable(String),
+    Uuid,
+    Counter,
+    RandomInt(i64, i64),
+    Timestamp,
+    FileName,
+}
+
+/// A string with the template expressions parsed out of it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+/// The values to use when evaluating the templates for a single request
+pub struct Context<'a> {
+    vars: &'a Variables,
+    file_name: Option<&'a str>,
+    counter: usize,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(vars: &'a Variables, file_name: Option<&'a str>) -> Self {
+        Self {
+            vars,
+            file_name,
+            counter: COUNTER.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+fn parse_expression(expr: &str) -> Result<Part, String> {
+    let mut words = expr.split_whitespace();
+    let name = words
+        .next()
+        .ok_or_else(|| "Empty template expression".to_string())?;
+    let args = words.collect::<Vec<_>>();
+    let part = match (name, args.as_slice()) {
+        ("uuid", []) => Part::Uuid,
+        ("counter", []) => Part::Counter,
+        ("timestamp", []) => Part::Timestamp,
+        ("file_name", []) => Part::FileName,
+        ("random_int", [min, max]) => {
+            let bound = |s: &str| {
+                s.parse::<i64>()
+                    .map_err(|e| format!("Invalid bound {} for random_int: {}", s, e))
+            };
+            let (min, max) = (bound(min)?, bound(max)?);
+            if min > max {
+                return Err(format!(
+                    "random_int minimum {} is above maximum {}",
+                    min, max
+                ));
+            }
+            Part::RandomInt(min, max)
+        }
+        ("env", [var]) => {
+            let var = var.trim_matches('"');
+            let value = env::var(var)
+                .map_err(|e| format!("Couldn't read environment variable {}: {}", var, e))?;
+            Part::Text(value)
+        }
+        (name, []) => Part::Variable(name.to_string()),
+        _ => return Err(format!("Unknown template expression {{{{{}}}}}", expr)),
+    };
+    Ok(part)
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = source;
+        let mut text = String::new();
+        while let Some(start) = rest.find("{{") {
+            if rest[..start].ends_with('\\') {
+                text.push_str(&rest[..start - 1]);
+                text.push_str("{{");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            text.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| format!("Unclosed {{{{ in {}", source))?;
+            if !text.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut text)));
+            }
+            parts.push(parse_expression(&rest[start + 2..start + end])?);
+            rest = &rest[start + end + 2..];
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    /// A template of the text as it is, without looking for expressions
+    pub fn text(s: &str) -> Self {
+        Self {
+            source: s.to_string(),
+            parts: vec![Part::Text(s.to_string())],
+        }
+    }
+
+    /// The string as written in the specification
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The value if there's nothing to evaluate for each request
+    pub fn constant(&self) -> Option<String> {
+        self.parts
+            .iter()
+            .map(|p| match p {
+                Part::Text(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Names of the variables used
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|p| match p {
+            Part::Variable(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    pub fn render(&self, ctx: &Context) -> Result<String, String> {
+        let mut res = String::with_capacity(self.source.len());
+        for part in &self.parts {
+            match part {
+                Part::Text(s) => res.push_str(s),
+                Part::Variable(name) => res.push_str(
+                    ctx.vars
+                        .get(name)
+                        .ok_or_else(|| format!("Unknown variable {}", name))?,
+                ),
+                Part::Uuid => res.push_str(&Uuid::new_v4().to_string()),
+                Part::Counter => res.push_str(&ctx.counter.to_string()),
+                Part::RandomInt(min, max) => {
+                    let value = rand::thread_rng().gen_range(*min..=*max);
+                    res.push_str(&value.to_string());
+                }
+                Part::Timestamp => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    res.push_str(&now.as_secs().to_string());
+                }
+                Part::FileName => res.push_str(ctx.file_name.unwrap_or_default()),
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// A URL made from a path joined onto a base, followed by extra path segments and query
+/// parameters, all of which can contain templates
+#[derive(Clone, Debug)]
+pub struct UrlTemplate {
+    base: Url,
+    path: Template,
+    segments: Vec<Template>,
+    query: Vec<(String, Template)>,
+}
+
+impl UrlTemplate {
+    pub fn new(
+        base: &Url,
+        path: Template,
+        segments: Vec<Template>,
+        query: Vec<(String, Template)>,
+    ) -> Self {
+        Self {
+            base: base.clone(),
+            path,
+            segments,
+            query,
+        }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.path.constant().is_some()
+            && self.segments.iter().all(|s| s.constant().is_some())
+            && self.query.iter().all(|(_, v)| v.constant().is_some())
+    }
+
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.path
+            .variables()
+            .chain(self.segments.iter().flat_map(|s| s.variables()))
+            .chain(self.query.iter().flat_map(|(_, v)| v.variables()))
+    }
+
+    /// The path and query as written in the specification, for naming the request in reports
+    pub fn name(&self) -> String {
+        let mut name = self.path.source().to_string();
+        for segment in &self.segments {
+            name.push('/');
+            name.push_str(segment.source());
+        }
+        let query = self
+            .query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v.source()))
+            .collect::<Vec<_>>();
+        if !query.is_empty() {
+            name.push('?');
+            name.push_str(&query.join("&"));
+        }
+        name
+    }
+
+    pub fn render(&self, ctx: &Context) -> Result<Url, String> {
+        let path = self.path.render(ctx)?;
+        let mut url = self
+            .base
+            .join(&path)
+            .map_err(|e| format!("Invalid URL {}: {}", path, e))?;
+        if !self.segments.is_empty() {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| "URL cannot be a base".to_string())?;
+            for segment in &self.segments {
+                segments.push(&segment.render(ctx)?);
+            }
+        }
+        for (name, value) in &self.query {
+            url.query_pairs_mut().append_pair(name, &value.render(ctx)?);
+        }
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_expressions() {
+        env::set_var("MURK_TEMPLATE_TEST", "secret");
+        let template = Template::parse(
+            "{{ id }}-{{counter}}-{{random_int 3 3}}-{{env \"MURK_TEMPLATE_TEST\"}}-{{file_name}}",
+        )
+        .unwrap();
+        assert_eq!(template.constant(), None);
+        assert_eq!(template.variables().collect::<Vec<_>>(), vec!["id"]);
+
+        let mut vars = Variables::new();
+        vars.insert("id".to_string(), "42".to_string());
+        let ctx = Context::new(&vars, Some("a.wav"));
+        let counter = ctx.counter;
+        assert_eq!(
+            template.render(&ctx).unwrap(),
+            format!("42-{}-3-secret-a.wav", counter)
+        );
+        let ctx = Context::new(&vars, None);
+        assert_eq!(ctx.counter, counter + 1);
+        assert_eq!(
+            Template::parse(r#"{"a": "\{{id}}"}"#).unwrap().constant(),
+            Some(r#"{"a": "{{id}}"}"#.to_string())
+        );
+        assert!(Template::parse("{{missing}}")
+            .unwrap()
+            .render(&ctx)
+            .is_err());
+
+        let uuid = Template::parse("{{uuid}}").unwrap();
+        let first = uuid.render(&ctx).unwrap();
+        assert!(Uuid::parse_str(&first).is_ok());
+        assert_ne!(first, uuid.render(&ctx).unwrap());
+
+        let timestamp = Template::parse("{{timestamp}}").unwrap().render(&ctx);
+        assert!(timestamp.unwrap().parse::<u64>().unwrap() > 1_600_000_000);
+
+        assert_eq!(
+            Template::parse("plain").unwrap().constant().unwrap(),
+            "plain"
+        );
+        assert!(Template::parse("{{id").is_err());
+        assert!(Template::parse("{{random_int 5 1}}").is_err());
+        assert!(Template::parse("{{unknown 1}}").is_err());
+    }
+
+    #[test]
+    fn render_url() {
+        let base = Url::parse("http://localhost/api/").unwrap();
+        let url = UrlTemplate::new(
+            &base,
+            Template::parse("/jobs/{{id}}").unwrap(),
+            vec![Template::parse("a b").unwrap()],
+            vec![(
+                "n".to_string(),
+                Template::parse("{{random_int 1 1}}").unwrap(),
+            )],
+        );
+        assert!(!url.is_constant());
+        assert_eq!(url.name(), "/jobs/{{id}}/a b?n={{random_int 1 1}}");
+        let mut vars = Variables::new();
+        vars.insert("id".to_string(), "7".to_string());
+        let url = url.render(&Context::new(&vars, None)).unwrap();
+        assert_eq!(url.as_str(), "http://localhost/jobs/7/a%20b?n=1");
+    }
+}